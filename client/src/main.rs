@@ -1,4 +1,4 @@
-use common::{Packet, PacketOwned};
+use common::{validation::strip_control, PacketOwned};
 use std::error::Error;
 use tui::{
     buffer::Buffer,
@@ -11,7 +11,17 @@ use tui::{
 extern crate common;
 extern crate crossbeam_channel;
 extern crate crossterm;
+extern crate ring;
+extern crate rustls;
+extern crate rustls_pemfile;
 extern crate tui;
+extern crate webpki;
+extern crate webpki_roots;
+
+mod tls;
+
+#[cfg(test)]
+mod tests;
 // use crossbeam_channel::{Receiver, Sender};
 
 use crossterm::{
@@ -27,6 +37,12 @@ static mut CLIENT_ID: u32 = 0;
 struct Message {
     author_id: u32,
     author_username: String,
+    /// Room the message was sent in, `None` for system and server messages
+    room: Option<String>,
+    /// Milliseconds since the Unix epoch given by the server, `None` for local messages
+    timestamp: Option<u64>,
+    /// Id given by the server, `None` for local messages
+    message_id: Option<u64>,
     message: String,
 }
 
@@ -57,7 +73,20 @@ impl StatefulWidget for MessageListWidget {
             .iter()
             .skip(state.message_scroll)
             .map(|x| {
-                Spans::from(vec![
+                let mut spans = Vec::with_capacity(6);
+                if let Some(timestamp) = x.timestamp {
+                    spans.push(Span::styled(
+                        format_time(timestamp),
+                        Style::default().fg(tui::style::Color::Gray),
+                    ));
+                }
+                if let Some(room) = &x.room {
+                    spans.push(Span::styled(
+                        format!("[{}] ", room),
+                        Style::default().fg(tui::style::Color::DarkGray),
+                    ));
+                }
+                spans.extend(vec![
                     Span::styled(x.author_username.as_str(), x.get_username_style()),
                     Span::styled(":", Style::default().fg(tui::style::Color::White)),
                     Span::styled(
@@ -68,7 +97,8 @@ impl StatefulWidget for MessageListWidget {
                         x.message.as_str(),
                         Style::default().fg(tui::style::Color::White),
                     ),
-                ])
+                ]);
+                Spans::from(spans)
             })
             .collect::<Vec<_>>();
         let paragraph = Paragraph::new(text).wrap(Wrap { trim: true });
@@ -77,6 +107,62 @@ impl StatefulWidget for MessageListWidget {
     }
 }
 
+/// Local time of a server timestamp, as shown in front of messages
+fn format_time(timestamp: u64) -> String {
+    use chrono::TimeZone;
+    chrono::Local
+        .timestamp_millis(timestamp as i64)
+        .format("%H:%M ")
+        .to_string()
+}
+
+/// Side panel listing the connected users
+struct UserListWidget;
+
+impl StatefulWidget for UserListWidget {
+    type State = UserList;
+
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        let mut users = state.users.iter().collect::<Vec<_>>();
+        users.sort_by_key(|(_, username)| username.to_lowercase());
+        let text = users
+            .into_iter()
+            .map(|(&id, username)| Spans::from(Span::styled(username.as_str(), user_style(id))))
+            .collect::<Vec<_>>();
+        Paragraph::new(text).render(area, buf);
+    }
+}
+
+/// Changes of the user list sent by the network thread to the interface
+#[derive(Debug, Clone)]
+enum RosterEvent {
+    /// The whole list, answer to a roster request
+    Set(Vec<(u32, String)>),
+    Joined(u32, String),
+    Left(u32),
+    /// A user changed its username, maybe us
+    Renamed(u32, String),
+}
+
+/// Commands sent by the interface to the network thread
+#[derive(Debug, Clone)]
+enum LocalCommand {
+    /// Leave the server with an optional reason
+    Quit(String),
+    /// Send a message in a room
+    Send { room: String, message: String },
+    /// Join a room
+    Join(String),
+    /// Leave a room
+    Part(String),
+    /// Send a message to a single user
+    PrivateMessage { target: String, message: String },
+    /// Fetch the messages of a room older than `before`
+    History { room: String, before: u64 },
+    /// Be known under another username
+    ChangeNick(String),
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum SystemUserType {
     System,
@@ -99,6 +185,28 @@ impl SystemUserType {
 }
 
 impl Message {
+    pub fn from_broadcast(packet: &common::ServerBroadcastMessageOwned) -> Self {
+        // The server cleans them, but an escape sequence would garble the terminal
+        Self {
+            author_id: packet.user_id,
+            author_username: strip_control(&packet.username),
+            room: Some(strip_control(&packet.room)),
+            timestamp: Some(packet.timestamp),
+            message_id: Some(packet.message_id),
+            message: strip_control(&packet.message),
+        }
+    }
+    /// A local notice, not sent by anyone
+    pub fn system(message: String) -> Self {
+        Self {
+            author_id: 0xF0_00_00_00,
+            author_username: String::from("System"),
+            room: None,
+            timestamp: None,
+            message_id: None,
+            message,
+        }
+    }
     pub fn is_system(&self) -> bool {
         (self.author_id & 0xF0000000) > 0
     }
@@ -114,10 +222,34 @@ impl Message {
         }
     }
     pub fn get_username_style(&self) -> tui::style::Style {
-        self.system_type().map(|s| s.get_style()).unwrap_or({
-            let [_, r, g, b] = (self.author_id >> 4).to_be_bytes();
-            tui::style::Style::default().fg(tui::style::Color::Rgb(r, b, g))
-        })
+        self.system_type()
+            .map(|s| s.get_style())
+            .unwrap_or_else(|| user_style(self.author_id))
+    }
+}
+
+/// Color of a user, derived from its id
+fn user_style(user_id: u32) -> tui::style::Style {
+    let [_, r, g, b] = (user_id >> 4).to_be_bytes();
+    tui::style::Style::default().fg(tui::style::Color::Rgb(r, b, g))
+}
+
+#[derive(Clone, Debug, Default)]
+struct UserList {
+    users: std::collections::HashMap<u32, String>,
+}
+
+impl UserList {
+    pub fn apply(&mut self, event: RosterEvent) {
+        match event {
+            RosterEvent::Set(users) => self.users = users.into_iter().collect(),
+            RosterEvent::Joined(id, username) | RosterEvent::Renamed(id, username) => {
+                self.users.insert(id, username);
+            }
+            RosterEvent::Left(id) => {
+                self.users.remove(&id);
+            }
+        }
     }
 }
 
@@ -125,45 +257,226 @@ impl Message {
 struct MessageList {
     inner_list: std::collections::VecDeque<Message>,
     message_scroll: usize,
+    // grows when older messages are fetched, up to MAX_SCROLLBACK
+    capacity: usize,
 }
 
 impl MessageList {
     const MAX_MESSAGE: usize = 100;
+    const MAX_SCROLLBACK: usize = 10_000;
+    /// Number of messages asked for each history request
+    const HISTORY_PAGE: u16 = 50;
 
     pub fn new() -> Self {
         Self {
             inner_list: std::collections::VecDeque::with_capacity(Self::MAX_MESSAGE),
             message_scroll: 0,
+            capacity: Self::MAX_MESSAGE,
         }
     }
     pub fn push_message(&mut self, message: Message) {
-        if self.len() + 1 > Self::MAX_MESSAGE {
+        if message.message_id.is_some() && self.contains(message.message_id) {
+            return;
+        }
+        if self.len() + 1 > self.capacity {
             self.inner_list.pop_front();
         }
         self.inner_list.push_back(message);
     }
 
+    /// Insert older messages at their place, returns how many were new
+    pub fn insert_history(&mut self, messages: Vec<Message>) -> usize {
+        let mut inserted = 0;
+        for message in messages {
+            let id = match message.message_id {
+                Some(id) if !self.contains(Some(id)) => id,
+                _ => continue,
+            };
+            let position = self
+                .inner_list
+                .iter()
+                .position(|m| m.message_id.map(|m| m > id).unwrap_or(false))
+                .unwrap_or_else(|| self.len());
+            self.inner_list.insert(position, message);
+            inserted += 1;
+        }
+        self.capacity = self.capacity.max(self.len()).min(Self::MAX_SCROLLBACK);
+        while self.len() > self.capacity {
+            self.inner_list.pop_front();
+        }
+        inserted
+    }
+
+    /// Id of the oldest message of `room` in the list
+    pub fn oldest_id(&self, room: &str) -> Option<u64> {
+        self.inner_list
+            .iter()
+            .filter(|m| m.room.as_deref() == Some(room))
+            .find_map(|m| m.message_id)
+    }
+
+    fn contains(&self, message_id: Option<u64>) -> bool {
+        self.inner_list.iter().any(|m| m.message_id == message_id)
+    }
+
     pub fn len(&self) -> usize {
         self.inner_list.len()
     }
 }
 
+/// The history requests of the rooms scrolled back in
+#[derive(Debug, Default)]
+struct Scrollback {
+    // room of the pending history request
+    pending: Option<String>,
+    // rooms without older messages on the server
+    done: std::collections::HashSet<String>,
+}
+
+impl Scrollback {
+    /// Whether older messages of `room` can be asked for now
+    pub fn can_request(&self, room: &str) -> bool {
+        self.pending.is_none() && !self.done.contains(room)
+    }
+
+    pub fn requested(&mut self, room: &str) {
+        self.pending = Some(room.to_string());
+    }
+
+    /// A page of `count` messages of `room` came back.
+    ///
+    /// The server can send fewer messages than asked, only an empty page ends the history.
+    pub fn received(&mut self, room: &str, count: usize) {
+        if count == 0 {
+            self.done.insert(room.to_string());
+        }
+        if self.pending.as_deref() == Some(room) {
+            self.pending = None;
+        }
+    }
+
+    /// Forget the pending request, its answer is for a room left behind
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+}
+
+/// The arguments of `line` if it is the command `name`, alone or followed by a space
+fn command<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    match line.strip_prefix(name)? {
+        "" => Some(""),
+        rest if rest.starts_with(' ') => Some(&rest[1..]),
+        _ => None,
+    }
+}
+
+/// Both a plain and a TLS connection to the server
+trait Stream: std::io::Read + std::io::Write {}
+impl<T: std::io::Read + std::io::Write> Stream for T {}
+
+/// Encode the packet and write its frame in one go
+fn write_packet(
+    connection: &mut dyn Stream,
+    packet: Result<impl common::frame::IntoFrame, common::LengthError>,
+) -> std::io::Result<()> {
+    let packet = packet.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut frame = Vec::with_capacity(packet.frame_len());
+    packet
+        .write_frame_to_vec(&mut frame)
+        .map_err(std::io::Error::other)?;
+    connection.write_all(&frame)
+}
+
+/// Take the `--tls`, `--tls-ca FILE` and `--tls-pin SHA256` flags out of the arguments
+fn split_tls_flags(
+    mut args: impl Iterator<Item = String>,
+) -> Result<(Vec<String>, Option<tls::TlsOptions>), String> {
+    let mut positional = Vec::new();
+    let mut options: Option<tls::TlsOptions> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tls" => {
+                options.get_or_insert_with(Default::default);
+            }
+            "--tls-ca" => {
+                let path = args
+                    .next()
+                    .ok_or("`--tls-ca` takes the path of a PEM file")?;
+                options.get_or_insert_with(Default::default).ca_file = Some(path.into());
+            }
+            "--tls-pin" => {
+                let pin = args
+                    .next()
+                    .ok_or("`--tls-pin` takes the SHA-256 fingerprint of a certificate")?;
+                let pin = tls::parse_pin(&pin)?;
+                options.get_or_insert_with(Default::default).pins.push(pin);
+            }
+            _ => positional.push(arg),
+        }
+    }
+    Ok((positional, options))
+}
+
+/// Read a password on the terminal without showing it
+fn read_password(prompt: &str) -> Result<String, Box<dyn Error>> {
+    use std::io::Write;
+
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+    enable_raw_mode()?;
+    let mut password = String::new();
+    let result = loop {
+        match event::read() {
+            Ok(CEvent::Key(key)) => match key.code {
+                KeyCode::Enter => break Ok(()),
+                KeyCode::Esc => break Err("no password given".into()),
+                KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    break Err("no password given".into())
+                }
+                KeyCode::Backspace => {
+                    password.pop();
+                }
+                KeyCode::Char(c) => password.push(c),
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(e) => break Err(e.into()),
+        }
+    };
+    disable_raw_mode()?;
+    eprintln!();
+    result.map(|()| password)
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = std::env::args();
-    let (_, server_ip, username) = (
-        args.next(),
-        {
-            let username = args
-                .next()
-                .expect("This programe take two argument: `serverip:port` and `username`");
-            username.trim()[..30.min(username.len())].to_string()
-        },
-        args.next()
-            .expect("This programe take two argument: `serverip:port` and `username`"),
-    );
+    let (mut args, tls_options) = split_tls_flags(std::env::args())?;
+    // `--password` asks for it, it can also be given in `MAIX_CHAT_PASSWORD`
+    let password = match args.iter().position(|arg| arg == "--password") {
+        Some(i) => {
+            args.remove(i);
+            Some(read_password("Password: ")?)
+        }
+        None => std::env::var("MAIX_CHAT_PASSWORD").ok(),
+    };
+    let mut args = args.into_iter().skip(1);
+    let (server_ip, username) = match (args.next(), args.next()) {
+        (Some(server_ip), Some(username)) => (server_ip, username),
+        _ => return Err("This programe take two argument: `serverip:port` and `username`".into()),
+    };
+    let mut username = common::validation::username(&username).map_err(|e| {
+        format!(
+            "`{}` isn't a valid username: {}",
+            username.escape_debug(),
+            e
+        )
+    })?;
     let (tx, rx) = crossbeam_channel::unbounded();
-    let (sender_message, _recv_message) = crossbeam_channel::unbounded::<Message>();
-    let (_sender_localmessage, recv_localmessage) = crossbeam_channel::unbounded::<Message>();
+    let (sender_message, recv_message) = crossbeam_channel::unbounded::<Message>();
+    let (sender_localcommand, recv_localcommand) = crossbeam_channel::unbounded::<LocalCommand>();
+    let (sender_roster, recv_roster) = crossbeam_channel::unbounded::<RosterEvent>();
+    let (sender_history, recv_history) = crossbeam_channel::unbounded::<(String, Vec<Message>)>();
+    // Rooms the server confirmed we joined
+    let (sender_joined, recv_joined) = crossbeam_channel::unbounded::<String>();
 
     std::thread::spawn(move || loop {
         if let CEvent::Key(key) = event::read().unwrap() {
@@ -175,99 +488,496 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let username2 = username.clone();
     std::thread::spawn(move || {
-        use common::{parser::FromBytes, serializer::IntoBytes};
+        use common::{
+            decoder::PacketDecoder,
+            protocol::{Capabilities, ReasonCode, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION},
+        };
         use std::{io::prelude::*, net};
         let sender_message = sender_message;
-        let _recv_localmessage = recv_localmessage;
-
-        let connection = net::TcpStream::connect(server_ip2.clone());
-
-        if let Err(_e) = connection.as_ref() {
-            sender_message
-                .send(Message {
-                    author_id: 0xF0_00_00_00,
-                    author_username: String::from("System"),
-                    message: format!("Failed to connect to server"),
-                })
-                .unwrap();
-            return;
-        }
-        let mut connection = connection.unwrap();
-        connection.set_nonblocking(true).unwrap();
-        connection.set_nodelay(true).unwrap();
-        connection
-            .set_read_timeout(Some(std::time::Duration::from_secs(20)))
-            .unwrap();
+        let recv_localcommand = recv_localcommand;
+        let sender_roster = sender_roster;
+        let sender_history = sender_history;
+        let sender_joined = sender_joined;
+
+        let connection = match &tls_options {
+            Some(options) => tls::connect(&server_ip2, options)
+                .map(|connection| Box::new(connection) as Box<dyn Stream>),
+            None => net::TcpStream::connect(server_ip2.clone()).and_then(|connection| {
+                connection.set_nonblocking(true)?;
+                connection.set_nodelay(true)?;
+                connection.set_read_timeout(Some(std::time::Duration::from_secs(20)))?;
+                Ok(Box::new(connection) as Box<dyn Stream>)
+            }),
+        };
+
+        let mut connection = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                sender_message
+                    .send(Message {
+                        author_id: 0xF0_00_00_00,
+                        author_username: String::from("System"),
+                        room: None,
+                        timestamp: None,
+                        message_id: None,
+                        message: format!("Failed to connect to server: {}", e),
+                    })
+                    .unwrap();
+                return;
+            }
+        };
         let mut status = 0u8; /*
                               0 => Not connected
                               1 => Server Confirmation
                               2 => Sent Confirmation
                               3 => Done
                               */
-        let mut buffer = Vec::with_capacity(256);
+        let mut buffer = [0u8; 4096];
         let mut packets = Vec::with_capacity(10);
+        let mut decoder = PacketDecoder::new();
         loop {
-            buffer.clear();
             packets.clear();
 
-            let res = connection.read(&mut buffer);
-            if let Err(_e) = res.as_ref() {
-                sender_message
-                    .send(Message {
-                        author_id: 0xF0_00_00_00,
-                        author_username: String::from("System"),
-                        message: format!("Failed to connect to server"),
-                    })
-                    .unwrap();
-                return;
+            while let Ok(command) = recv_localcommand.try_recv() {
+                let res = match command {
+                    LocalCommand::Quit(message) => {
+                        if status == 3 {
+                            let _ = write_packet(
+                                &mut *connection,
+                                common::ClientQuit::new(
+                                    unsafe { CLIENT_ID },
+                                    unsafe { CLIENT_MAGIC },
+                                    &message,
+                                ),
+                            );
+                        }
+                        return;
+                    }
+                    // Commands are only valid once registered
+                    _ if status != 3 => Ok(()),
+                    LocalCommand::Send { room, message } => write_packet(
+                        &mut *connection,
+                        common::ClientSendMessage::new(
+                            unsafe { CLIENT_ID },
+                            unsafe { CLIENT_MAGIC },
+                            &room,
+                            &message,
+                        ),
+                    ),
+                    LocalCommand::Join(room) => write_packet(
+                        &mut *connection,
+                        common::ClientJoinRoom::new(
+                            unsafe { CLIENT_ID },
+                            unsafe { CLIENT_MAGIC },
+                            &room,
+                        ),
+                    ),
+                    LocalCommand::Part(room) => write_packet(
+                        &mut *connection,
+                        common::ClientPartRoom::new(
+                            unsafe { CLIENT_ID },
+                            unsafe { CLIENT_MAGIC },
+                            &room,
+                        ),
+                    ),
+                    LocalCommand::PrivateMessage { target, message } => write_packet(
+                        &mut *connection,
+                        common::ClientPrivateMessage::new(
+                            unsafe { CLIENT_ID },
+                            unsafe { CLIENT_MAGIC },
+                            0,
+                            &target,
+                            &message,
+                        ),
+                    ),
+                    LocalCommand::History { room, before } => write_packet(
+                        &mut *connection,
+                        common::ClientHistoryRequest::new(
+                            unsafe { CLIENT_ID },
+                            unsafe { CLIENT_MAGIC },
+                            &room,
+                            before,
+                            MessageList::HISTORY_PAGE,
+                        ),
+                    ),
+                    LocalCommand::ChangeNick(username) => write_packet(
+                        &mut *connection,
+                        common::ClientChangeNick::new(
+                            unsafe { CLIENT_ID },
+                            unsafe { CLIENT_MAGIC },
+                            &username,
+                        ),
+                    ),
+                };
+                if let Err(_e) = res {
+                    sender_message
+                        .send(Message {
+                            author_id: 0xF0_00_00_00,
+                            author_username: String::from("System"),
+                            room: None,
+                            timestamp: None,
+                            message_id: None,
+                            message: String::from("Failed to send to server"),
+                        })
+                        .expect("Error when sending system message");
+                    return;
+                }
+            }
+
+            match connection.read(&mut buffer) {
+                Ok(0) => {
+                    sender_message
+                        .send(Message {
+                            author_id: 0xF0_00_00_00,
+                            author_username: String::from("System"),
+                            room: None,
+                            timestamp: None,
+                            message_id: None,
+                            message: String::from("Connection closed by server"),
+                        })
+                        .unwrap();
+                    return;
+                }
+                Ok(n) => decoder.feed(&buffer[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(_e) => {
+                    sender_message
+                        .send(Message {
+                            author_id: 0xF0_00_00_00,
+                            author_username: String::from("System"),
+                            room: None,
+                            timestamp: None,
+                            message_id: None,
+                            message: String::from("Failed to connect to server"),
+                        })
+                        .unwrap();
+                    return;
+                }
             }
-            let mut data = &buffer[..res.unwrap()];
 
             loop {
-                eprint!(".");
-                let res = Packet::from_bytes(data);
-                if let Ok((new_data, packet)) = res {
-                    data = new_data;
-                    packets.push(packet.into_owned());
-                    eprintln!("{:?} {:?}", &data, &packet);
-                } else {
-                    break;
+                match decoder.next_packet() {
+                    Ok(Some(packet)) => packets.push(packet),
+                    Ok(None) => break,
+                    Err(_) => {}
+                }
+            }
+
+            for packet in &packets {
+                match packet {
+                    PacketOwned::ServerError(packet) => sender_message
+                        .send(Message {
+                            author_id: 0xE0_00_00_00,
+                            author_username: String::from("Server"),
+                            room: None,
+                            timestamp: None,
+                            message_id: None,
+                            message: format!(
+                                "Error: {} ({:?})",
+                                strip_control(&packet.message),
+                                ReasonCode::from_code(packet.code)
+                            ),
+                        })
+                        .expect("Error when sending system message"),
+                    PacketOwned::ServerMotd(packet) => sender_message
+                        .send(Message {
+                            author_id: 0xE0_00_00_00,
+                            author_username: String::from("Server"),
+                            room: None,
+                            timestamp: None,
+                            message_id: None,
+                            message: strip_control(&packet.message),
+                        })
+                        .expect("Error when sending system message"),
+                    PacketOwned::ServerDisconnect(packet) => {
+                        sender_message
+                            .send(Message {
+                                author_id: 0xE0_00_00_00,
+                                author_username: String::from("Server"),
+                                room: None,
+                                timestamp: None,
+                                message_id: None,
+                                message: format!(
+                                    "Disconnected: {} ({:?})",
+                                    strip_control(&packet.message),
+                                    ReasonCode::from_code(packet.code)
+                                ),
+                            })
+                            .expect("Error when sending system message");
+                        return;
+                    }
+                    PacketOwned::ServerUserLeft(packet) => {
+                        let _ = sender_roster.send(RosterEvent::Left(packet.user_id));
+                        sender_message
+                            .send(Message {
+                                author_id: 0xE0_00_00_00,
+                                author_username: String::from("Server"),
+                                room: None,
+                                timestamp: None,
+                                message_id: None,
+                                message: if packet.message.is_empty() {
+                                    format!("{} left", strip_control(&packet.username))
+                                } else {
+                                    format!(
+                                        "{} left ({})",
+                                        strip_control(&packet.username),
+                                        strip_control(&packet.message)
+                                    )
+                                },
+                            })
+                            .expect("Error when sending system message")
+                    }
+                    PacketOwned::ServerNickChanged(packet) => {
+                        let _ = sender_roster.send(RosterEvent::Renamed(
+                            packet.user_id,
+                            strip_control(&packet.username),
+                        ));
+                        sender_message
+                            .send(Message {
+                                author_id: 0xE0_00_00_00,
+                                author_username: String::from("Server"),
+                                room: None,
+                                timestamp: None,
+                                message_id: None,
+                                message: format!(
+                                    "{} is now known as {}",
+                                    strip_control(&packet.old_username),
+                                    strip_control(&packet.username)
+                                ),
+                            })
+                            .expect("Error when sending system message")
+                    }
+                    PacketOwned::ServerUserJoined(packet) => {
+                        let _ = sender_roster.send(RosterEvent::Joined(
+                            packet.user_id,
+                            strip_control(&packet.username),
+                        ));
+                    }
+                    PacketOwned::ServerRoster(packet) => {
+                        let _ = sender_roster.send(RosterEvent::Set(
+                            packet
+                                .users
+                                .iter()
+                                .map(|user| (user.user_id, strip_control(&user.username)))
+                                .collect(),
+                        ));
+                    }
+                    PacketOwned::HeartBeatRequest(_) if status == 3 => {
+                        let _ = write_packet(
+                            &mut *connection,
+                            Ok(common::HeartBeatSend {
+                                client_id: unsafe { CLIENT_ID },
+                                magic: unsafe { CLIENT_MAGIC },
+                            }),
+                        );
+                    }
+                    PacketOwned::ServerBroadcastMessage(packet) => sender_message
+                        .send(Message::from_broadcast(packet))
+                        .expect("Error when sending message"),
+                    PacketOwned::ServerHistory(packet) => {
+                        let _ = sender_history.send((
+                            strip_control(&packet.room),
+                            packet
+                                .messages
+                                .iter()
+                                .map(Message::from_broadcast)
+                                .collect(),
+                        ));
+                    }
+                    PacketOwned::ServerPrivateMessage(packet) => sender_message
+                        .send(Message {
+                            author_id: packet.user_id,
+                            author_username: strip_control(&packet.username),
+                            room: Some(String::from("private")),
+                            timestamp: None,
+                            message_id: None,
+                            message: strip_control(&packet.message),
+                        })
+                        .expect("Error when sending message"),
+                    PacketOwned::ServerRoomJoined(packet) => {
+                        if packet.user_id == unsafe { CLIENT_ID } {
+                            let _ = sender_joined.send(packet.room.clone());
+                        }
+                        sender_message
+                            .send(Message {
+                                author_id: 0xE0_00_00_00,
+                                author_username: String::from("Server"),
+                                room: Some(strip_control(&packet.room)),
+                                timestamp: None,
+                                message_id: None,
+                                message: format!("{} joined", strip_control(&packet.username)),
+                            })
+                            .expect("Error when sending system message")
+                    }
+                    PacketOwned::ServerRoomParted(packet) => sender_message
+                        .send(Message {
+                            author_id: 0xE0_00_00_00,
+                            author_username: String::from("Server"),
+                            room: Some(strip_control(&packet.room)),
+                            timestamp: None,
+                            message_id: None,
+                            message: format!("{} left the room", strip_control(&packet.username)),
+                        })
+                        .expect("Error when sending system message"),
+                    _ => {}
                 }
             }
 
             match status {
                 0 => {
-                    let res = connection.write(
-                        &common::ClientRegistrationRequest {
-                            username_len: username2.len() as u8,
-                            username: username2.as_str(),
-                        }
-                        .unwrap_bytes()[..],
+                    let res = write_packet(
+                        &mut *connection,
+                        common::ClientRegistrationRequest::new(
+                            PROTOCOL_VERSION,
+                            Capabilities::SUPPORTED.bits(),
+                            &username2,
+                        ),
                     );
                     if let Err(_e) = res.as_ref() {
                         sender_message
                             .send(Message {
                                 author_id: 0xF0_00_00_00,
                                 author_username: String::from("System"),
-                                message: format!("Failed to connect to server"),
+                                room: None,
+                                timestamp: None,
+                                message_id: None,
+                                message: String::from("Failed to connect to server"),
                             })
                             .expect("Error when sending system message");
                         return;
                     }
-                    eprintln!("fdsfq");
                     status = 1;
                 }
                 1 => {
-                    if let Some(PacketOwned::ServerRegistrationConfirmation(packet)) =
-                        packets.get(1)
-                    {
-                        unsafe {
-                            CLIENT_ID = packet.client_id;
-                            CLIENT_MAGIC = packet.magic;
+                    for packet in &packets {
+                        match packet {
+                            PacketOwned::ServerRegistrationConfirmation(packet) => {
+                                if packet.protocol_version < MIN_PROTOCOL_VERSION {
+                                    sender_message
+                                        .send(Message {
+                                            author_id: 0xF0_00_00_00,
+                                            author_username: String::from("System"),
+                                            room: None,
+                                            timestamp: None,
+                                            message_id: None,
+                                            message: format!(
+                                                "Server protocol v{} is too old, we need at least v{}",
+                                                packet.protocol_version, MIN_PROTOCOL_VERSION
+                                            ),
+                                        })
+                                        .expect("Error when sending system message");
+                                    return;
+                                }
+                                unsafe {
+                                    CLIENT_ID = packet.client_id;
+                                    CLIENT_MAGIC = packet.magic;
+                                }
+                                status = 2;
+                            }
+                            PacketOwned::ServerAuthChallenge(packet) => {
+                                // The username has an account, prove we know its password
+                                let proof = password.as_ref().and_then(|password| {
+                                    common::auth::client_proof(
+                                        password,
+                                        &packet.salt,
+                                        packet.iterations,
+                                        &username2,
+                                        &packet.nonce,
+                                    )
+                                });
+                                let proof = match proof {
+                                    Some(proof) => proof,
+                                    None => {
+                                        sender_message
+                                            .send(Message {
+                                                author_id: 0xF0_00_00_00,
+                                                author_username: String::from("System"),
+                                                room: None,
+                                                timestamp: None,
+                                                message_id: None,
+                                                message: String::from(
+                                                    "This username needs a password, start with `--password` or set MAIX_CHAT_PASSWORD",
+                                                ),
+                                            })
+                                            .expect("Error when sending system message");
+                                        return;
+                                    }
+                                };
+                                let res = write_packet(
+                                    &mut *connection,
+                                    Ok(common::ClientAuthResponse { proof }),
+                                );
+                                if res.is_err() {
+                                    sender_message
+                                        .send(Message {
+                                            author_id: 0xF0_00_00_00,
+                                            author_username: String::from("System"),
+                                            room: None,
+                                            timestamp: None,
+                                            message_id: None,
+                                            message: String::from("Failed to connect to server"),
+                                        })
+                                        .expect("Error when sending system message");
+                                    return;
+                                }
+                            }
+                            PacketOwned::ServerRegistrationRefusal(packet) => {
+                                sender_message
+                                    .send(Message {
+                                        author_id: 0xE0_00_00_00,
+                                        author_username: String::from("Server"),
+                                        room: None,
+                                        timestamp: None,
+                                        message_id: None,
+                                        message: format!(
+                                            "Registration refused: {} (server speaks v{} to v{})",
+                                            strip_control(&packet.reason),
+                                            packet.min_version,
+                                            packet.server_version
+                                        ),
+                                    })
+                                    .expect("Error when sending system message");
+                                return;
+                            }
+                            _ => {}
                         }
                     }
                 }
-                2 => {}
+                2 => {
+                    let res = write_packet(
+                        &mut *connection,
+                        Ok(common::ClientRegistrationEnd {
+                            client_id: unsafe { CLIENT_ID },
+                            magic: unsafe { CLIENT_MAGIC },
+                        }),
+                    )
+                    .and_then(|_| {
+                        // Ask who is already there, joins and leaves are pushed after that
+                        write_packet(
+                            &mut *connection,
+                            Ok(common::ClientRosterRequest {
+                                client_id: unsafe { CLIENT_ID },
+                                magic: unsafe { CLIENT_MAGIC },
+                            }),
+                        )
+                    });
+                    if let Err(_e) = res.as_ref() {
+                        sender_message
+                            .send(Message {
+                                author_id: 0xF0_00_00_00,
+                                author_username: String::from("System"),
+                                room: None,
+                                timestamp: None,
+                                message_id: None,
+                                message: String::from("Failed to connect to server"),
+                            })
+                            .expect("Error when sending system message");
+                        return;
+                    }
+                    status = 3;
+                }
                 3 => {}
 
                 _ => unreachable!(),
@@ -276,9 +986,14 @@ fn main() -> Result<(), Box<dyn Error>> {
     });
 
     let mut message_list = MessageList::new();
+    let mut user_list = UserList::default();
+    let mut scrollback = Scrollback::default();
     message_list.push_message(Message {
         author_id: 0xF0_00_00_00,
         author_username: String::from("System"),
+        room: None,
+        timestamp: None,
+        message_id: None,
         message: format!(
             "Connecting to `{}` with username: `{}`",
             &server_ip, username
@@ -303,59 +1018,158 @@ fn main() -> Result<(), Box<dyn Error>> {
     let backend = tui::backend::CrosstermBackend::new(stdout);
     let mut terminal = tui::Terminal::new(backend)?;
     let mut message_string = String::with_capacity(250);
+    // Room the messages typed are sent to
+    let mut current_room = String::from(common::protocol::DEFAULT_ROOM);
     loop {
+        while let Ok(event) = recv_roster.try_recv() {
+            if let RosterEvent::Renamed(id, new_username) = &event {
+                if *id == unsafe { CLIENT_ID } {
+                    username = new_username.clone();
+                }
+            }
+            user_list.apply(event);
+        }
+        // The room typed is switched to once the server let us in
+        while let Ok(room) = recv_joined.try_recv() {
+            if room != current_room {
+                current_room = room;
+                scrollback.cancel();
+            }
+        }
+        while let Ok((room, messages)) = recv_history.try_recv() {
+            scrollback.received(&room, messages.len());
+            message_list.insert_history(messages);
+        }
+        while let Ok(message) = recv_message.try_recv() {
+            message_list.push_message(message);
+            if (message_list.len() as u16) > terminal.size()?.height - 6
+                && message_list.message_scroll < message_list.capacity
+            {
+                message_list.message_scroll += if message_list.len() == message_list.capacity {
+                    0
+                } else {
+                    1
+                };
+            }
+        }
         match rx.recv_timeout(std::time::Duration::from_millis(50)) {
             Ok(key_event) => match key_event.code {
                 KeyCode::Backspace | KeyCode::Delete => {
                     message_string.pop();
                 }
-                KeyCode::Enter => {
-                    /* Placeholder */
-                    if message_string.len() > 0 {
-                        if unsafe { CLIENT_ID } != 0 {
-                            message_list.push_message(Message {
-                                author_id: unsafe { CLIENT_ID },
-                                author_username: username.clone(),
-                                message: message_string.clone(),
-                            });
-                            message_string.clear();
-                            if (message_list.len() as u16) > terminal.size()?.height - 6
-                                && message_list.message_scroll < MessageList::MAX_MESSAGE
-                            {
-                                message_list.message_scroll +=
-                                    if message_list.len() == MessageList::MAX_MESSAGE {
-                                        0
-                                    } else {
-                                        1
-                                    };
+                KeyCode::Enter if command(&message_string, "/quit").is_some() => {
+                    let reason = command(&message_string, "/quit")
+                        .and_then(|reason| common::validation::message(reason).ok())
+                        .unwrap_or_default();
+                    let _ = sender_localcommand.send(LocalCommand::Quit(reason));
+                    disable_raw_mode()?;
+                    break;
+                }
+                KeyCode::Enter if message_string.starts_with("/join ") => {
+                    let room = message_string["/join ".len()..].trim().to_string();
+                    if !room.is_empty() {
+                        let _ = sender_localcommand.send(LocalCommand::Join(room));
+                    }
+                    message_string.clear();
+                }
+                KeyCode::Enter if command(&message_string, "/part").is_some() => {
+                    let room = match command(&message_string, "/part").unwrap_or_default().trim() {
+                        "" => current_room.clone(),
+                        room => room.to_string(),
+                    };
+                    if room == current_room {
+                        current_room = String::from(common::protocol::DEFAULT_ROOM);
+                        scrollback.cancel();
+                    }
+                    let _ = sender_localcommand.send(LocalCommand::Part(room));
+                    message_string.clear();
+                }
+                KeyCode::Enter if message_string.starts_with("/nick ") => {
+                    match common::validation::username(&message_string["/nick ".len()..]) {
+                        Ok(new_username) => {
+                            let _ =
+                                sender_localcommand.send(LocalCommand::ChangeNick(new_username));
+                        }
+                        Err(e) => message_list
+                            .push_message(Message::system(format!("Invalid username: {}", e))),
+                    }
+                    message_string.clear();
+                }
+                KeyCode::Enter
+                    if message_string.starts_with("/msg ") && unsafe { CLIENT_ID } != 0 =>
+                {
+                    let mut args = message_string["/msg ".len()..].trim().splitn(2, ' ');
+                    if let (Some(target), Some(message)) = (args.next(), args.next()) {
+                        match common::validation::message(message) {
+                            Ok(message) => {
+                                // The server doesn't echo private messages back
+                                message_list.push_message(Message {
+                                    author_id: unsafe { CLIENT_ID },
+                                    author_username: username.clone(),
+                                    room: Some(format!("to {}", target)),
+                                    timestamp: None,
+                                    message_id: None,
+                                    message: message.clone(),
+                                });
+                                let _ = sender_localcommand.send(LocalCommand::PrivateMessage {
+                                    target: target.to_string(),
+                                    message,
+                                });
                             }
+                            Err(e) => message_list
+                                .push_message(Message::system(format!("Invalid message: {}", e))),
                         }
                     }
-                    /* Send message */
+                    message_string.clear();
                 }
-                KeyCode::Down | KeyCode::PageDown => {
-                    if (message_list.len() as u16) > terminal.size()?.height - 6
-                        && message_list.message_scroll < MessageList::MAX_MESSAGE
-                    {
-                        message_list.message_scroll += 1;
+                KeyCode::Enter if !message_string.is_empty() && unsafe { CLIENT_ID } != 0 => {
+                    match common::validation::message(&message_string) {
+                        Ok(message) => {
+                            let _ = sender_localcommand.send(LocalCommand::Send {
+                                room: current_room.clone(),
+                                message,
+                            });
+                        }
+                        Err(e) => message_list
+                            .push_message(Message::system(format!("Invalid message: {}", e))),
                     }
+                    message_string.clear();
+                }
+                KeyCode::Down | KeyCode::PageDown
+                    if (message_list.len() as u16) > terminal.size()?.height - 6
+                        && message_list.message_scroll < message_list.len() =>
+                {
+                    message_list.message_scroll += 1;
+                }
+                // Scrolling past the top fetches older messages of the current room
+                KeyCode::Up | KeyCode::PageUp
+                    if message_list.message_scroll == 0
+                        && scrollback.can_request(&current_room)
+                        && unsafe { CLIENT_ID } != 0 =>
+                {
+                    let before = message_list.oldest_id(&current_room).unwrap_or(u64::MAX);
+                    let _ = sender_localcommand.send(LocalCommand::History {
+                        room: current_room.clone(),
+                        before,
+                    });
+                    scrollback.requested(&current_room);
                 }
                 KeyCode::Up | KeyCode::PageUp => {
                     message_list.message_scroll = message_list.message_scroll.saturating_sub(1)
                 }
                 KeyCode::Insert => {}
-                KeyCode::Char(chr) => {
-                    if message_string.len() <= 250 {
-                        message_string.push(chr);
-                    }
+                KeyCode::Char(chr) if message_string.len() <= 250 => {
+                    message_string.push(chr);
                 }
                 KeyCode::Esc => {
+                    let _ = sender_localcommand.send(LocalCommand::Quit(String::new()));
                     disable_raw_mode()?;
                     break;
                 }
                 _ => {}
             },
             Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                let _ = sender_localcommand.send(LocalCommand::Quit(String::new()));
                 disable_raw_mode()?;
                 break;
             }
@@ -364,17 +1178,36 @@ fn main() -> Result<(), Box<dyn Error>> {
 
         terminal.draw(|f| {
             let rects = layout.split(f.size());
+            let top = tui::layout::Layout::default()
+                .direction(tui::layout::Direction::Horizontal)
+                .constraints(
+                    [
+                        tui::layout::Constraint::Min(20),
+                        tui::layout::Constraint::Length(24),
+                    ]
+                    .as_ref(),
+                )
+                .split(rects[0]);
             let message_block = tui::widgets::Block::default()
                 .title(format!("Messages on {}", server_ip))
                 .borders(tui::widgets::Borders::ALL)
                 .style(tui::style::Style::default().fg(tui::style::Color::Red));
-            let message_block_inner = message_block.inner(rects[0]);
+            let message_block_inner = message_block.inner(top[0]);
 
-            f.render_widget(message_block, rects[0]);
+            f.render_widget(message_block, top[0]);
             f.render_stateful_widget(MessageListWidget, message_block_inner, &mut message_list);
 
+            let user_block = tui::widgets::Block::default()
+                .title(format!("Users ({})", user_list.users.len()))
+                .borders(tui::widgets::Borders::ALL)
+                .style(tui::style::Style::default().fg(tui::style::Color::Green));
+            let user_block_inner = user_block.inner(top[1]);
+
+            f.render_widget(user_block, top[1]);
+            f.render_stateful_widget(UserListWidget, user_block_inner, &mut user_list);
+
             let input_block = tui::widgets::Block::default()
-                .title("Input")
+                .title(format!("Input [{}]", current_room))
                 .borders(tui::widgets::Borders::ALL)
                 .style(tui::style::Style::default().fg(tui::style::Color::Yellow));
 
@@ -400,6 +1233,11 @@ fn main() -> Result<(), Box<dyn Error>> {
             f.render_widget(render_text, rects[1]);
         })?;
     }
+    // Give the network thread some time to say goodbye, it drops its sender when done
+    while recv_message
+        .recv_timeout(std::time::Duration::from_secs(1))
+        .is_ok()
+    {}
     println!();
     Ok(())
 }
@@ -0,0 +1,150 @@
+mod tls {
+    use crate::tls::PinnedVerifier;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::{
+        client::danger::ServerCertVerifier,
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, ServerName, UnixTime},
+    };
+    use std::{convert::TryFrom, sync::Arc};
+
+    fn pin(cert: &CertificateDer<'_>) -> [u8; 32] {
+        let mut pin = [0u8; 32];
+        pin.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, cert).as_ref());
+        pin
+    }
+
+    fn verify(
+        verifier: &PinnedVerifier,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+    ) -> bool {
+        verifier
+            .verify_server_cert(
+                end_entity,
+                intermediates,
+                &ServerName::try_from("localhost").unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+            .is_ok()
+    }
+
+    fn self_signed() -> CertificateDer<'static> {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .unwrap()
+            .cert
+            .der()
+            .clone()
+    }
+
+    #[test]
+    fn pinned_leaf() {
+        let pinned = self_signed();
+        let verifier =
+            PinnedVerifier::new(None, vec![pin(&pinned)], Arc::new(default_provider())).unwrap();
+        assert!(verify(&verifier, &pinned, &[]));
+        // The handshake is signed with the key of the leaf, a copy of the pinned
+        // certificate sent after another leaf proves nothing
+        let other = self_signed();
+        assert!(!verify(&verifier, &other, std::slice::from_ref(&pinned)));
+    }
+
+    #[test]
+    fn pinned_chain() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        let leaf_key = KeyPair::generate().unwrap();
+        let leaf = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&leaf_key, &ca, &ca_key)
+            .unwrap();
+        let unrelated = self_signed();
+
+        let verifier = |pins| {
+            PinnedVerifier::new(
+                Some(vec![ca.der().clone()]),
+                pins,
+                Arc::new(default_provider()),
+            )
+            .unwrap()
+        };
+        assert!(verify(&verifier(vec![pin(ca.der())]), leaf.der(), &[]));
+        assert!(verify(&verifier(vec![pin(leaf.der())]), leaf.der(), &[]));
+        // Not on the path to the CA
+        assert!(!verify(
+            &verifier(vec![pin(&unrelated)]),
+            leaf.der(),
+            std::slice::from_ref(&unrelated)
+        ));
+        // Not signed by the CA
+        assert!(!verify(&verifier(vec![pin(&unrelated)]), &unrelated, &[]));
+    }
+}
+
+mod args {
+    use crate::split_tls_flags;
+
+    fn split(args: &[&str]) -> Result<Vec<String>, String> {
+        split_tls_flags(args.iter().map(|arg| arg.to_string())).map(|(args, _)| args)
+    }
+
+    #[test]
+    fn tls_flags() {
+        let (args, options) = split_tls_flags(
+            [
+                "chat",
+                "--tls-pin",
+                &"ab:".repeat(32)[..95],
+                "host:1",
+                "maix",
+            ]
+            .iter()
+            .map(|arg| arg.to_string()),
+        )
+        .unwrap();
+        assert_eq!(args, ["chat", "host:1", "maix"]);
+        assert_eq!(options.unwrap().pins, vec![[0xAB; 32]]);
+
+        assert!(split(&["chat", "--tls-ca"]).is_err());
+        assert!(split(&["chat", "--tls-pin"]).is_err());
+        assert!(split(&["chat", "--tls-pin", "abcd"]).is_err());
+    }
+}
+
+mod scrollback {
+    use crate::Scrollback;
+
+    #[test]
+    fn short_pages() {
+        let mut scrollback = Scrollback::default();
+        scrollback.requested("general");
+        assert!(!scrollback.can_request("general"));
+        // The server has a smaller page limit, there may be more
+        scrollback.received("general", 2);
+        assert!(scrollback.can_request("general"));
+        scrollback.requested("general");
+        scrollback.received("general", 1);
+        assert!(scrollback.can_request("general"));
+        scrollback.requested("general");
+        scrollback.received("general", 0);
+        assert!(!scrollback.can_request("general"));
+        assert!(scrollback.can_request("dev"));
+    }
+}
+
+mod commands {
+    use crate::command;
+
+    #[test]
+    fn tokens() {
+        assert_eq!(command("/part", "/part"), Some(""));
+        assert_eq!(command("/part dev", "/part"), Some("dev"));
+        assert_eq!(command("/party tonight", "/part"), None);
+        assert_eq!(command("hello", "/part"), None);
+        assert_eq!(command("/quit see you", "/quit"), Some("see you"));
+        assert_eq!(command("/quitter", "/quit"), None);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader},
+    net::TcpStream,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{ring::default_provider, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, TrustAnchor, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    StreamOwned,
+};
+
+/// How the certificate of the server is checked
+#[derive(Debug, Default, Clone)]
+pub struct TlsOptions {
+    /// PEM file with the CAs to trust instead of the usual ones
+    pub ca_file: Option<PathBuf>,
+    /// SHA-256 fingerprints, the certificate of the server or with a CA file one on its
+    /// verified chain must have one of them
+    pub pins: Vec<[u8; 32]>,
+}
+
+/// Parse a SHA-256 fingerprint written in hex, the bytes can be separated by `:`
+pub fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("`{}` isn't a SHA-256 fingerprint", pin));
+    }
+    let mut fingerprint = [0u8; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("`{}` isn't a SHA-256 fingerprint", pin))?;
+    }
+    Ok(fingerprint)
+}
+
+/// Connect to `addr` and do the handshake, the socket is non-blocking once it returns
+pub fn connect(
+    addr: &str,
+    options: &TlsOptions,
+) -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let host = match addr.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return Err(invalid(format!("`{}` has no port", addr))),
+    };
+    let server_name = ServerName::try_from(host.to_string()).map_err(|e| invalid(e.to_string()))?;
+
+    let provider = Arc::new(default_provider());
+    let cas = match &options.ca_file {
+        Some(path) => Some(
+            rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => None,
+    };
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let config = if options.pins.is_empty() {
+        let roots = match cas {
+            Some(cas) => {
+                let mut roots = RootCertStore::empty();
+                for cert in cas {
+                    roots.add(cert).map_err(io::Error::other)?;
+                }
+                roots
+            }
+            None => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier::new(
+                cas,
+                options.pins.clone(),
+                provider,
+            )?))
+            .with_no_client_auth()
+    };
+
+    let mut conn =
+        ClientConnection::new(Arc::new(config), server_name).map_err(io::Error::other)?;
+    let mut sock = TcpStream::connect(addr)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock)?;
+    }
+    sock.set_nonblocking(true)?;
+    sock.set_nodelay(true)?;
+    Ok(StreamOwned::new(conn, sock))
+}
+
+/// Check the certificate of the server against pinned fingerprints
+#[derive(Debug)]
+pub(crate) struct PinnedVerifier {
+    // A pinned self-signed certificate has no CA, the chain is only checked with a CA file
+    chain: Option<Chain>,
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+#[derive(Debug)]
+struct Chain {
+    verifier: Arc<WebPkiServerVerifier>,
+    // the CAs of the file, with their trust anchor to find the one a chain ends at
+    cas: Vec<(TrustAnchor<'static>, CertificateDer<'static>)>,
+}
+
+impl PinnedVerifier {
+    /// Pin `pins`, with the chain checked against `cas` when there are some
+    pub(crate) fn new(
+        cas: Option<Vec<CertificateDer<'static>>>,
+        pins: Vec<[u8; 32]>,
+        provider: Arc<CryptoProvider>,
+    ) -> io::Result<Self> {
+        let chain = match cas {
+            Some(cas) => {
+                let mut roots = RootCertStore::empty();
+                let mut anchors = Vec::with_capacity(cas.len());
+                for cert in cas {
+                    let anchor = webpki::anchor_from_trusted_cert(&cert)
+                        .map_err(io::Error::other)?
+                        .to_owned();
+                    roots.add(cert.clone()).map_err(io::Error::other)?;
+                    anchors.push((anchor, cert));
+                }
+                let verifier =
+                    WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                        .build()
+                        .map_err(io::Error::other)?;
+                Some(Chain {
+                    verifier,
+                    cas: anchors,
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            chain,
+            pins,
+            provider,
+        })
+    }
+
+    fn is_pinned(&self, cert: &[u8]) -> bool {
+        let fingerprint = ring::digest::digest(&ring::digest::SHA256, cert);
+        self.pins.iter().any(|pin| pin[..] == *fingerprint.as_ref())
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // Only a certificate the server proved it owns, or one on the verified path to a CA,
+        // can match: anyone can send a copy of the pinned certificate along with their own
+        let pinned = match &self.chain {
+            None => self.is_pinned(end_entity),
+            Some(chain) => {
+                chain.verifier.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                )?;
+                let cert = webpki::EndEntityCert::try_from(end_entity)
+                    .map_err(|e| rustls::Error::General(e.to_string()))?;
+                let anchors = chain
+                    .cas
+                    .iter()
+                    .map(|(anchor, _)| anchor.clone())
+                    .collect::<Vec<_>>();
+                let path = cert
+                    .verify_for_usage(
+                        self.provider.signature_verification_algorithms.all,
+                        &anchors,
+                        intermediates,
+                        now,
+                        webpki::KeyUsage::server_auth(),
+                        None,
+                        None,
+                    )
+                    .map_err(|e| rustls::Error::General(e.to_string()))?;
+                let ca = chain
+                    .cas
+                    .iter()
+                    .find(|(anchor, _)| anchor == path.anchor())
+                    .map(|(_, cert)| cert);
+                self.is_pinned(end_entity)
+                    || path
+                        .intermediate_certificates()
+                        .any(|cert| self.is_pinned(&cert.der()))
+                    || ca.is_some_and(|ca| self.is_pinned(ca))
+            }
+        };
+        if pinned {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "no certificate of the server matches a pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
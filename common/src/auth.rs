@@ -0,0 +1,92 @@
+//! Challenge-response login, the password never leaves the client.
+//!
+//! This is the key exchange of SCRAM-SHA-256 (RFC 5802) without its text encoding:
+//! - the server only keeps `stored_key(password, salt, iterations)`;
+//! - it sends the salt, the iterations and a fresh nonce in `ServerAuthChallenge`;
+//! - the client answers with `client_proof` in `ClientAuthResponse`;
+//! - the server checks it with `verify_proof`.
+//!
+//! A stolen account file isn't enough to log in, and neither is a recorded proof.
+use std::num::NonZeroU32;
+
+use ring::{digest, hmac, pbkdf2};
+
+/// Length of the salt of an account
+pub const SALT_LEN: usize = 16;
+/// Length of the nonce of a challenge
+pub const NONCE_LEN: usize = 32;
+/// Length of the keys and proofs
+pub const KEY_LEN: usize = 32;
+/// PBKDF2 iterations used for new passwords
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+
+fn client_key(password: &str, salt: &[u8], iterations: NonZeroU32) -> [u8; KEY_LEN] {
+    let mut salted_password = [0u8; KEY_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        password.as_bytes(),
+        &mut salted_password,
+    );
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &salted_password);
+    let mut client_key = [0u8; KEY_LEN];
+    client_key.copy_from_slice(hmac::sign(&key, b"Client Key").as_ref());
+    client_key
+}
+
+fn hash(client_key: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let mut stored_key = [0u8; KEY_LEN];
+    stored_key.copy_from_slice(digest::digest(&digest::SHA256, client_key).as_ref());
+    stored_key
+}
+
+fn signature(stored_key: &[u8; KEY_LEN], username: &str, nonce: &[u8]) -> hmac::Tag {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, stored_key);
+    let mut context = hmac::Context::with_key(&key);
+    context.update(username.as_bytes());
+    context.update(nonce);
+    context.sign()
+}
+
+/// What the server keeps of a password, `None` if `iterations` is 0
+pub fn stored_key(password: &str, salt: &[u8], iterations: u32) -> Option<[u8; KEY_LEN]> {
+    let iterations = NonZeroU32::new(iterations)?;
+    Some(hash(&client_key(password, salt, iterations)))
+}
+
+/// Answer to a challenge, `None` if `iterations` is 0
+pub fn client_proof(
+    password: &str,
+    salt: &[u8],
+    iterations: u32,
+    username: &str,
+    nonce: &[u8],
+) -> Option<[u8; KEY_LEN]> {
+    let mut proof = client_key(password, salt, NonZeroU32::new(iterations)?);
+    let signature = signature(&hash(&proof), username, nonce);
+    for (byte, mask) in proof.iter_mut().zip(signature.as_ref()) {
+        *byte ^= mask;
+    }
+    Some(proof)
+}
+
+/// Check the answer to a challenge
+pub fn verify_proof(
+    stored_key: &[u8; KEY_LEN],
+    username: &str,
+    nonce: &[u8],
+    proof: &[u8; KEY_LEN],
+) -> bool {
+    let signature = signature(stored_key, username, nonce);
+    let mut client_key = *proof;
+    for (byte, mask) in client_key.iter_mut().zip(signature.as_ref()) {
+        *byte ^= mask;
+    }
+    // Constant time, the attacker must not learn how many bytes are right
+    hash(&client_key)
+        .iter()
+        .zip(stored_key)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
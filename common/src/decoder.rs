@@ -0,0 +1,77 @@
+use crate::{
+    frame::{Frame, FrameDecoder, FrameError},
+    parser::ParserError,
+    PacketOwned,
+};
+
+/// Stateful decoder turning arbitrary chunks of a byte stream into packets.
+///
+/// Bytes are given with `feed` as they are read from the socket, and complete
+/// packets are taken out with `next_packet`. Anything that isn't a complete
+/// frame yet stays buffered until the next `feed`.
+#[derive(Debug, Clone, Default)]
+pub struct PacketDecoder {
+    buffer: Vec<u8>,
+    // Start of the bytes that haven't been consumed yet
+    start: usize,
+    frames: FrameDecoder,
+    unknown: usize,
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_payload(max_payload: usize) -> Self {
+        Self {
+            frames: FrameDecoder::with_max_payload(max_payload),
+            ..Self::default()
+        }
+    }
+
+    /// Append data read from the stream
+    pub fn feed(&mut self, data: &[u8]) {
+        if self.start > 0 {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Get the next complete packet.
+    ///
+    /// `Ok(None)` means more data is needed, an error means a whole packet was
+    /// malformed and has been thrown away: the decoder can still be used.
+    pub fn next_packet(&mut self) -> Result<Option<PacketOwned>, ParserError> {
+        loop {
+            let (consumed, frame) = self.frames.decode(&self.buffer[self.start..]);
+            let frame = frame.map(|frame| match frame {
+                Frame::Packet(packet) => Some(packet.into_owned()),
+                Frame::Unknown(_) => None,
+            });
+            self.start += consumed;
+            match frame {
+                Ok(Some(packet)) => return Ok(Some(packet)),
+                Ok(None) => self.unknown += 1,
+                Err(FrameError::Incomplete(_)) => return Ok(None),
+                Err(FrameError::Malformed(_, e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Number of bytes buffered waiting for the rest of their frame
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    /// Number of well framed packets skipped because their tag is unknown
+    pub fn unknown(&self) -> usize {
+        self.unknown
+    }
+
+    /// Number of bytes skipped while looking for the start of a frame
+    pub fn skipped(&self) -> usize {
+        self.frames.skipped()
+    }
+}
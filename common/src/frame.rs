@@ -0,0 +1,236 @@
+extern crate cookie_factory as cookie;
+
+use std::{convert::TryFrom, io::Write};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::{
+    parser::{FromBytes, ParserError, ParserErrorKind},
+    serializer::{IntoBytes, SerializeError, LENGTH_MISMATCH},
+    Packet,
+};
+/*
+Frame:
+    => b"MC" + (tag.len() + body.len()) as u32 + tag + body
+
+Every packet sent on the wire is wrapped in a frame, the length allows a reader
+to skip packets it does not know about and the marker allows it to find the
+start of the next frame if the stream got corrupted.
+*/
+
+/// Marker starting every frame
+pub const FRAME_MARKER: [u8; 2] = *b"MC";
+/// Size of the marker and the length field
+pub const FRAME_HEADER_SIZE: usize = 2 + 4;
+/// Default maximum size of a frame payload (tag + body)
+pub const DEFAULT_MAX_PAYLOAD: usize = 1 << 20;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Frame<'a> {
+    /// A frame holding a packet known by this version of the protocol
+    Packet(Packet<'a>),
+    /// A well formed frame with a tag this version doesn't know, it was skipped
+    Unknown([u8; 3]),
+}
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("Incomplete frame, at least {0} more bytes are needed")]
+    Incomplete(usize),
+    /// The error tells the packet and the field
+    #[error("Malformed packet, {1}")]
+    Malformed([u8; 3], ParserError),
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    max_payload: usize,
+    skipped: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_max_payload(DEFAULT_MAX_PAYLOAD)
+    }
+
+    pub fn with_max_payload(max_payload: usize) -> Self {
+        Self {
+            max_payload,
+            skipped: 0,
+        }
+    }
+
+    /// Total number of bytes thrown away while looking for a frame marker
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Decode the first frame of `input`.
+    ///
+    /// Returns the number of bytes consumed along with the result; the consumed
+    /// bytes must be removed from the input before calling `decode` again, even
+    /// on error. Garbage before a frame is skipped and counted as consumed.
+    /// On `FrameError::Incomplete` the remaining bytes must be kept and more
+    /// data appended to them.
+    pub fn decode<'a>(&mut self, input: &'a [u8]) -> (usize, Result<Frame<'a>, FrameError>) {
+        let mut offset = 0;
+        loop {
+            let rest = &input[offset..];
+            match rest.windows(2).position(|w| w == FRAME_MARKER) {
+                Some(pos) => self.skip(&mut offset, pos),
+                None => {
+                    // The last byte may be the start of a marker, keep it
+                    let keep = (rest.last() == Some(&FRAME_MARKER[0])) as usize;
+                    self.skip(&mut offset, rest.len() - keep);
+                    return (
+                        offset,
+                        Err(FrameError::Incomplete(FRAME_HEADER_SIZE - keep)),
+                    );
+                }
+            }
+
+            let rest = &input[offset..];
+            if rest.len() < FRAME_HEADER_SIZE {
+                return (
+                    offset,
+                    Err(FrameError::Incomplete(FRAME_HEADER_SIZE - rest.len())),
+                );
+            }
+            let mut len = [0; 4];
+            len.copy_from_slice(&rest[2..FRAME_HEADER_SIZE]);
+            let len = u32::from_be_bytes(len) as usize;
+            // This can't be a frame: the marker was part of some garbage
+            if len < 3 || len > self.max_payload {
+                self.skip(&mut offset, 1);
+                continue;
+            }
+
+            let total = FRAME_HEADER_SIZE + len;
+            if rest.len() < total {
+                return (offset, Err(FrameError::Incomplete(total - rest.len())));
+            }
+            let payload = &rest[FRAME_HEADER_SIZE..total];
+            offset += total;
+
+            let tag = [payload[0], payload[1], payload[2]];
+            let res = match Packet::from_bytes(payload) {
+                Ok((_, packet)) => Ok(Frame::Packet(packet)),
+                // only the tag of the frame itself, a bad tag in a list is a malformed packet
+                Err(nom::Err::Failure(e)) | Err(nom::Err::Error(e))
+                    if e.kind == ParserErrorKind::InvalidTag && e.field.is_none() =>
+                {
+                    Ok(Frame::Unknown(tag))
+                }
+                // the payload is all there is, a field going past it is malformed
+                Err(nom::Err::Failure(e)) | Err(nom::Err::Error(e)) => {
+                    Err(FrameError::Malformed(tag, e))
+                }
+                // the parsers report a field going past its input as a failure, should one ask
+                // for more anyway the payload is still all there is
+                Err(nom::Err::Incomplete(needed)) => Err(FrameError::Malformed(
+                    tag,
+                    ParserError {
+                        tag: Some(tag),
+                        field: None,
+                        offset: 0,
+                        kind: ParserErrorKind::Incomplete {
+                            expected: payload.len()
+                                + match needed {
+                                    nom::Needed::Size(size) => size.get(),
+                                    nom::Needed::Unknown => 1,
+                                },
+                            actual: payload.len(),
+                        },
+                    },
+                )),
+            };
+            return (offset, res);
+        }
+    }
+
+    fn skip(&mut self, offset: &mut usize, count: usize) {
+        *offset += count;
+        self.skipped += count;
+    }
+}
+
+pub trait IntoFrame: IntoBytes {
+    /// Number of bytes of the packet once framed
+    fn frame_len(&self) -> usize {
+        FRAME_HEADER_SIZE + self.encoded_len()
+    }
+
+    /// Write the frame header then the packet to the context
+    fn serialize_frame<W: Write>(
+        &self,
+        context: cookie::WriteContext<W>,
+    ) -> Result<cookie::WriteContext<W>, SerializeError> {
+        let len = self.encoded_len();
+        let len = u32::try_from(len).map_err(|_| SerializeError::TooBig(len))?;
+        let context = cookie::combinator::slice(&FRAME_MARKER)(context)?;
+        let context = cookie::bytes::be_u32(len)(context)?;
+        Ok(self.serialize(context)?)
+    }
+
+    fn into_frame(&self) -> cookie::GenResult<Vec<u8>> {
+        let raw_buffer: Vec<u8> = Vec::with_capacity(self.frame_len());
+        let context = cookie::WriteContext::from(raw_buffer);
+        self.serialize_frame(context).map_err(|e| match e {
+            SerializeError::Io(e) => cookie::GenError::IoError(e),
+            SerializeError::TooBig(len) => cookie::GenError::BufferTooBig(len),
+            _ => cookie::GenError::CustomError(LENGTH_MISMATCH),
+        })
+    }
+    fn unwrap_frame(&self) -> Vec<u8> {
+        self.into_frame().unwrap().into_inner().0
+    }
+
+    /// Write the framed packet to `writer` without allocating, returns the number of bytes
+    /// written.
+    ///
+    /// The writer may have received part of the frame when an error is returned.
+    fn write_frame_to<W: Write>(&self, writer: W) -> Result<usize, SerializeError> {
+        let context = self.serialize_frame(cookie::WriteContext::from(writer))?;
+        Ok(context.position as usize)
+    }
+
+    /// Write the framed packet at the start of `buffer`, returns the number of bytes written
+    fn write_frame_to_slice(&self, buffer: &mut [u8]) -> Result<usize, SerializeError> {
+        let needed = self.frame_len();
+        if needed > buffer.len() {
+            return Err(SerializeError::BufferTooSmall {
+                needed,
+                available: buffer.len(),
+            });
+        }
+        self.write_frame_to(buffer)
+    }
+
+    /// Append the framed packet to `buffer`, returns the number of bytes written.
+    ///
+    /// `buffer` is left as it was when an error is returned.
+    fn write_frame_to_bytes_mut(&self, buffer: &mut BytesMut) -> Result<usize, SerializeError> {
+        let start = buffer.len();
+        buffer.reserve(self.frame_len());
+        self.write_frame_to((&mut *buffer).writer())
+            .inspect_err(|_| buffer.truncate(start))
+    }
+
+    /// Append the framed packet to `buffer`, returns the number of bytes written.
+    ///
+    /// `buffer` is left as it was when an error is returned.
+    fn write_frame_to_vec(&self, buffer: &mut Vec<u8>) -> Result<usize, SerializeError> {
+        let start = buffer.len();
+        buffer.reserve(self.frame_len());
+        self.write_frame_to(&mut *buffer)
+            .inspect_err(|_| buffer.truncate(start))
+    }
+}
+
+impl<T: IntoBytes + ?Sized> IntoFrame for T {}
@@ -1,270 +1,429 @@
+#![allow(clippy::wrong_self_convention)]
 #[macro_use]
 extern crate thiserror;
 
+pub mod auth;
+pub mod decoder;
+pub mod frame;
 pub mod parser;
+pub mod protocol;
 pub mod serializer;
+pub mod validation;
+
+use std::convert::TryFrom;
+
+use maix_chat_derive::{Packet, Packets};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[cfg(test)]
 mod tests;
 /*
+Every packet is sent inside a frame, see `frame`.
+
+With the `serde` feature, every packet is also written in JSON as an object of its fields,
+with the name of its `Packet` variant in `type`:
+    {"type":"ServerMotd","message":"hello"}
+The `*_len` fields are left out, they are derived from their field when read back.
+Deserialize into `PacketOwned`, a borrowed packet can't hold a string with escapes.
+
 Client Registration Request         (crr):
-    => b"crr" + username.len() + username;
+    => b"crr" + protocol_version + capabilities + username.len() + username;
 Server Registration Confirmation    (src):
-    => b"src" + clientID + Magic;
+    => b"src" + clientID + Magic + protocol_version + capabilities;
+Server Registration Refusal         (srf):
+    => b"srf" + server_version + min_version + reason.len() + reason;
+Server Auth Challenge               (sac):
+    => b"sac" + salt[16] + iterations + nonce[32];
+Client Auth Response                (cau):
+    => b"cau" + proof[32];
 ClientRegistrationEnd               (cre)
     => b"cre" + clientID + magic
 Client Send Message                 (csm):
-    => b"csm" + clientID + magic + message.len() + message;
+    => b"csm" + clientID + magic + room.len() + room + message.len() + message;
+Client Quit                         (cqt):
+    => b"cqt" + clientID + magic + message.len() + message;
+Client Join Room                    (cjr):
+    => b"cjr" + clientID + magic + room.len() + room;
+Client Part Room                    (cpr):
+    => b"cpr" + clientID + magic + room.len() + room;
+Client Private Message              (cpm):
+    => b"cpm" + clientID + magic + targetID + target.len() + target + message.len() + message;
+Client Roster Request               (cro):
+    => b"cro" + clientID + magic;
+Client History Request              (chr):
+    => b"chr" + clientID + magic + room.len() + room + before + limit;
+Client Change Nick                  (ccn):
+    => b"ccn" + clientID + magic + username.len() + username;
+Server Error                        (ser):
+    => b"ser" + code + message.len() + message;
+Server Disconnect                   (sdc):
+    => b"sdc" + code + message.len() + message;
+Server User Left                    (sul):
+    => b"sul" + userID + username.len() + username + message.len() + message;
 Server Broadcast Message            (sbm):
-    => b"sbm" + username.len() + username +message.len() + message;
+    => b"sbm" + messageID + timestamp + userID + room.len() + room + username.len() + username + message.len() + message;
+Server Room Joined                  (srj):
+    => b"srj" + userID + username.len() + username + room.len() + room;
+Server Room Parted                  (srp):
+    => b"srp" + userID + username.len() + username + room.len() + room;
+Server User Joined                  (suj):
+    => b"suj" + userID + username.len() + username;
+Server Roster                       (sro):
+    => b"sro" + users.len() + (userID + username.len() + username) * users.len();
+Server History                      (shs):
+    => b"shs" + room.len() + room + messages.len() + sbm_packet * messages.len();
+Server Private Message              (spm):
+    => b"spm" + userID + username.len() + username + message.len() + message;
+Server Message Of The Day           (smo):
+    => b"smo" + message.len() + message;
+Server Nick Changed                 (snc):
+    => b"snc" + userID + old_username.len() + old_username + username.len() + username;
 Heart Beat Request                  (hbr):
     => b"hbr"
 Heart Beat Send                     (hbs):
     => b"hbs" + clientID + magic
 */
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Packets)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(tag = "type", bound(deserialize = "'de: 'a"))
+)]
 #[non_exhaustive]
 pub enum Packet<'a> {
     ClientRegistrationRequest(ClientRegistrationRequest<'a>),
     ClientRegistrationEnd(ClientRegistrationEnd),
     ClientSendMessage(ClientSendMessage<'a>),
+    ClientQuit(ClientQuit<'a>),
+    ClientJoinRoom(ClientJoinRoom<'a>),
+    ClientPartRoom(ClientPartRoom<'a>),
+    ClientPrivateMessage(ClientPrivateMessage<'a>),
+    ClientRosterRequest(ClientRosterRequest),
+    ClientHistoryRequest(ClientHistoryRequest<'a>),
+    ClientAuthResponse(ClientAuthResponse),
+    ClientChangeNick(ClientChangeNick<'a>),
     HeartBeatSend(HeartBeatSend),
 
     ServerRegistrationConfirmation(ServerRegistrationConfirmation),
+    ServerRegistrationRefusal(ServerRegistrationRefusal<'a>),
+    ServerError(ServerError<'a>),
+    ServerDisconnect(ServerDisconnect<'a>),
+    ServerUserLeft(ServerUserLeft<'a>),
     ServerBroadcastMessage(ServerBroadcastMessage<'a>),
+    ServerRoomJoined(ServerRoomJoined<'a>),
+    ServerRoomParted(ServerRoomParted<'a>),
+    ServerPrivateMessage(ServerPrivateMessage<'a>),
+    ServerUserJoined(ServerUserJoined<'a>),
+    ServerRoster(ServerRoster<'a>),
+    ServerHistory(ServerHistory<'a>),
+    ServerMotd(ServerMotd<'a>),
+    ServerAuthChallenge(ServerAuthChallenge),
+    ServerNickChanged(ServerNickChanged<'a>),
     HeartBeatRequest(HeartBeatRequest),
 }
 
-impl<'a> Packet<'a> {
-    pub fn into_owned(&self) -> PacketOwned {
-        macro_rules! into_owned {
-            (($($changed:ident),*),($($owned:ident),*)) => {
-                match self {
-                    $(Packet::$owned(inner) => PacketOwned::$owned(*inner),)*
-                    $(Packet::$changed(inner) => PacketOwned::$changed(inner.into_owned()),)*
-                }
-            };
-        }
-
-        into_owned!(
-            (
-                ClientRegistrationRequest,
-                ClientSendMessage,
-                ServerBroadcastMessage
-            ),
-            (
-                ClientRegistrationEnd,
-                HeartBeatSend,
-                HeartBeatRequest,
-                ServerRegistrationConfirmation
-            )
-        )
-    }
-}
-
-impl<'a> ClientRegistrationRequest<'a> {
-    pub fn into_owned(&self) -> ClientRegistrationRequestOwned {
-        ClientRegistrationRequestOwned {
-            username_len: self.username_len,
-            username: self.username.to_owned(),
-        }
-    }
-}
-
-impl<'a> ClientSendMessage<'a> {
-    pub fn into_owned(&self) -> ClientSendMessageOwned {
-        ClientSendMessageOwned {
-            client_id: self.client_id,
-            magic: self.magic,
-            message_len: self.message_len,
-            message: self.message.to_owned(),
-        }
-    }
-}
-
-impl<'a> ServerBroadcastMessage<'a> {
-    pub fn into_owned(&self) -> ServerBroadcastMessageOwned {
-        ServerBroadcastMessageOwned {
-            user_id: self.user_id,
-            username_len: self.username_len,
-            username: self.username.to_owned(),
-            message_len: self.message_len,
-            message: self.message.to_owned(),
-        }
-    }
-}
-#[derive(Debug, Clone, Eq, PartialEq)]
-#[non_exhaustive]
-pub enum PacketOwned {
-    ClientRegistrationRequest(ClientRegistrationRequestOwned),
-    ClientRegistrationEnd(ClientRegistrationEnd),
-    ClientSendMessage(ClientSendMessageOwned),
-    HeartBeatSend(HeartBeatSend),
+/// A string or a list too long for the length field sent before it, returned by the `new`
+/// constructors of the packets
+#[derive(Error, Debug, Clone, Copy, Eq, PartialEq)]
+#[error("`{field}` has a length of {len}, at most {max} fits")]
+pub struct LengthError {
+    pub field: &'static str,
+    pub len: usize,
+    pub max: usize,
+}
 
-    ServerRegistrationConfirmation(ServerRegistrationConfirmation),
-    ServerBroadcastMessage(ServerBroadcastMessageOwned),
-    HeartBeatRequest(HeartBeatRequest),
+// Integer types a length is sent in
+trait LengthField: TryFrom<usize> {
+    const MAX: usize;
+}
+
+impl LengthField for u8 {
+    const MAX: usize = u8::MAX as usize;
+}
+
+impl LengthField for u16 {
+    const MAX: usize = u16::MAX as usize;
 }
 
-impl<'a> Packet<'a> {
-    pub fn get_identifier(&self) -> [u8; 3] {
-        use Packet::*;
-        match self {
-            ClientRegistrationRequest(inner) => inner.get_identifier(),
-            ClientRegistrationEnd(inner) => inner.get_identifier(),
-            ClientSendMessage(inner) => inner.get_identifier(),
-            HeartBeatSend(inner) => inner.get_identifier(),
-
-            ServerBroadcastMessage(inner) => inner.get_identifier(),
-            ServerRegistrationConfirmation(inner) => inner.get_identifier(),
-            HeartBeatRequest(inner) => inner.get_identifier(),
-        }
-    }
-}
-
-impl PacketOwned {
-    pub fn get_identifier(&self) -> [u8; 3] {
-        use PacketOwned::*;
-        match self {
-            ClientRegistrationRequest(inner) => inner.get_identifier(),
-            ClientRegistrationEnd(inner) => inner.get_identifier(),
-            ClientSendMessage(inner) => inner.get_identifier(),
-            HeartBeatSend(inner) => inner.get_identifier(),
-
-            ServerBroadcastMessage(inner) => inner.get_identifier(),
-            ServerRegistrationConfirmation(inner) => inner.get_identifier(),
-            HeartBeatRequest(inner) => inner.get_identifier(),
-        }
-    }
-}
-
-impl ClientRegistrationEnd {
-    const IDENTIFIER: [u8; 3] = *b"cre";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-
-impl<'a> ClientRegistrationRequest<'a> {
-    const IDENTIFIER: [u8; 3] = *b"crr";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-impl<'a> ClientSendMessage<'a> {
-    const IDENTIFIER: [u8; 3] = *b"csm";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-
-impl ClientRegistrationRequestOwned {
-    const IDENTIFIER: [u8; 3] = *b"crr";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-impl ClientSendMessageOwned {
-    const IDENTIFIER: [u8; 3] = *b"csm";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-impl HeartBeatSend {
-    const IDENTIFIER: [u8; 3] = *b"hbs";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-impl<'a> ServerBroadcastMessage<'a> {
-    const IDENTIFIER: [u8; 3] = *b"sbm";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-
-impl ServerBroadcastMessageOwned {
-    const IDENTIFIER: [u8; 3] = *b"sbm";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-impl ServerRegistrationConfirmation {
-    const IDENTIFIER: [u8; 3] = *b"src";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-impl HeartBeatRequest {
-    const IDENTIFIER: [u8; 3] = *b"hbr";
-    pub fn get_identifier(&self) -> [u8; 3] {
-        Self::IDENTIFIER
-    }
-}
-
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+// The length field of `field`, if it fits in it
+fn length<T: LengthField>(field: &'static str, len: usize) -> Result<T, LengthError> {
+    T::try_from(len).map_err(|_| LengthError {
+        field,
+        len,
+        max: T::MAX,
+    })
+}
+
+// Every packet derives `Packet`, its wire format follows its fields, see `maix-chat-derive`.
+// Adding a packet is writing its struct and its variant of `Packet`.
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Packet)]
+#[packet(tag = "cre")]
 pub struct ClientRegistrationEnd {
     pub client_id: u32,
     pub magic: u32,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "crr")]
 pub struct ClientRegistrationRequest<'a> {
+    pub protocol_version: u16,
+    pub capabilities: u32,
     pub username_len: u8,
     pub username: &'a str,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub struct ClientRegistrationRequestOwned {
-    pub username_len: u8,
-    pub username: String,
-}
-
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Packet)]
+#[packet(tag = "src")]
 pub struct ServerRegistrationConfirmation {
     pub client_id: u32,
     pub magic: u32,
+    pub protocol_version: u16,
+    pub capabilities: u32,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "srf")]
+pub struct ServerRegistrationRefusal<'a> {
+    pub server_version: u16,
+    pub min_version: u16,
+    pub reason_len: u16,
+    pub reason: &'a str,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "csm")]
 pub struct ClientSendMessage<'a> {
     pub client_id: u32,
     pub magic: u32,
+    pub room_len: u8,
+    pub room: &'a str,
+    pub message_len: u16,
+    pub message: &'a str,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "ser")]
+pub struct ServerError<'a> {
+    pub code: u16,
     pub message_len: u16,
     pub message: &'a str,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub struct ClientSendMessageOwned {
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "sdc")]
+pub struct ServerDisconnect<'a> {
+    pub code: u16,
+    pub message_len: u16,
+    pub message: &'a str,
+}
+
+/// An empty message means the client didn't give a reason
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "cqt")]
+pub struct ClientQuit<'a> {
     pub client_id: u32,
     pub magic: u32,
     pub message_len: u16,
-    pub message: String,
+    pub message: &'a str,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// An empty message means the user didn't give a reason
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "sul")]
+pub struct ServerUserLeft<'a> {
+    pub user_id: u32,
+    pub username_len: u8,
+    pub username: &'a str,
+    pub message_len: u16,
+    pub message: &'a str,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "sbm")]
 pub struct ServerBroadcastMessage<'a> {
+    /// Increasing id given by the server to every message
+    pub message_id: u64,
+    /// Milliseconds since the Unix epoch (UTC) when the server got the message
+    pub timestamp: u64,
     pub user_id: u32,
+    pub room_len: u8,
+    pub room: &'a str,
     pub username_len: u8,
     pub username: &'a str,
     pub message_len: u16,
     pub message: &'a str,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub struct ServerBroadcastMessageOwned {
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "cjr")]
+pub struct ClientJoinRoom<'a> {
+    pub client_id: u32,
+    pub magic: u32,
+    pub room_len: u8,
+    pub room: &'a str,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "cpr")]
+pub struct ClientPartRoom<'a> {
+    pub client_id: u32,
+    pub magic: u32,
+    pub room_len: u8,
+    pub room: &'a str,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "srj")]
+pub struct ServerRoomJoined<'a> {
     pub user_id: u32,
     pub username_len: u8,
-    pub username: String,
+    pub username: &'a str,
+    pub room_len: u8,
+    pub room: &'a str,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "srp")]
+pub struct ServerRoomParted<'a> {
+    pub user_id: u32,
+    pub username_len: u8,
+    pub username: &'a str,
+    pub room_len: u8,
+    pub room: &'a str,
+}
+
+/// Message for a single user, addressed by `target` or by `target_id` if `target` is empty
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "cpm")]
+pub struct ClientPrivateMessage<'a> {
+    pub client_id: u32,
+    pub magic: u32,
+    pub target_id: u32,
+    pub target_len: u8,
+    pub target: &'a str,
+    pub message_len: u16,
+    pub message: &'a str,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "spm")]
+pub struct ServerPrivateMessage<'a> {
+    pub user_id: u32,
+    pub username_len: u8,
+    pub username: &'a str,
+    pub message_len: u16,
+    pub message: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Packet)]
+#[packet(tag = "cro")]
+pub struct ClientRosterRequest {
+    pub client_id: u32,
+    pub magic: u32,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "suj")]
+pub struct ServerUserJoined<'a> {
+    pub user_id: u32,
+    pub username_len: u8,
+    pub username: &'a str,
+}
+
+/// Every registered user, `users_len` is the number of entries
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "sro")]
+pub struct ServerRoster<'a> {
+    pub users_len: u16,
+    pub users: Vec<RosterEntry<'a>>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+pub struct RosterEntry<'a> {
+    pub user_id: u32,
+    pub username_len: u8,
+    pub username: &'a str,
+}
+
+/// Ask for at most `limit` messages of `room` older than the message `before`
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "chr")]
+pub struct ClientHistoryRequest<'a> {
+    pub client_id: u32,
+    pub magic: u32,
+    pub room_len: u8,
+    pub room: &'a str,
+    pub before: u64,
+    pub limit: u16,
+}
+
+/// Answer to `ClientHistoryRequest`, oldest message first.
+///
+/// The server can send fewer messages than requested, it has a limit of its own: only an
+/// empty page means there is nothing older left.
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "shs")]
+pub struct ServerHistory<'a> {
+    pub room_len: u8,
+    pub room: &'a str,
+    pub messages_len: u16,
+    pub messages: Vec<ServerBroadcastMessage<'a>>,
+}
+
+/// Message of the day, sent once the registration is done
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "smo")]
+pub struct ServerMotd<'a> {
     pub message_len: u16,
-    pub message: String,
+    pub message: &'a str,
+}
+
+/// Sent instead of `ServerRegistrationConfirmation` when the username has an account, see `auth`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Packet)]
+#[packet(tag = "sac")]
+pub struct ServerAuthChallenge {
+    pub salt: [u8; auth::SALT_LEN],
+    pub iterations: u32,
+    pub nonce: [u8; auth::NONCE_LEN],
+}
+
+/// Answer to `ServerAuthChallenge`, see `auth`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Packet)]
+#[packet(tag = "cau")]
+pub struct ClientAuthResponse {
+    pub proof: [u8; auth::KEY_LEN],
+}
+
+/// Ask to be known under another username, answered by `ServerNickChanged` or `ServerError`
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "ccn")]
+pub struct ClientChangeNick<'a> {
+    pub client_id: u32,
+    pub magic: u32,
+    pub username_len: u8,
+    pub username: &'a str,
+}
+
+/// Broadcasted when a user changes its username
+#[derive(Debug, Clone, Eq, PartialEq, Packet)]
+#[packet(tag = "snc")]
+pub struct ServerNickChanged<'a> {
+    pub user_id: u32,
+    pub old_username_len: u8,
+    pub old_username: &'a str,
+    pub username_len: u8,
+    pub username: &'a str,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Packet)]
+#[packet(tag = "hbr")]
 pub struct HeartBeatRequest {}
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Packet)]
+#[packet(tag = "hbs")]
 pub struct HeartBeatSend {
     pub client_id: u32,
     pub magic: u32,
@@ -1,226 +1,191 @@
 pub extern crate nom;
 
-use crate::{
-    ClientRegistrationEnd, ClientRegistrationRequest, ClientSendMessage, HeartBeatRequest,
-    HeartBeatSend, Packet, ServerBroadcastMessage, ServerRegistrationConfirmation,
-};
-use nom::bytes::complete as bytes;
+use std::fmt;
+
 use nom::IResult;
 
 pub trait FromBytes<'a>
 where
     Self: 'a + Sized,
 {
+    /// Fewest bytes a value of this type is sent in, to not trust a list length blindly
+    const MIN_LEN: usize;
+
     fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError>;
 }
 
-#[derive(Error, Debug)]
-pub enum ParserError {
-    #[error("Invalid tag")]
+/// Why a packet couldn't be read, and where
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ParserError {
+    /// Tag read at the start of the packet, `None` if there weren't 3 bytes
+    pub tag: Option<[u8; 3]>,
+    /// Field that couldn't be read, `None` for the tag
+    pub field: Option<&'static str>,
+    /// Offset of the field from the start of the packet
+    pub offset: usize,
+    pub kind: ParserErrorKind,
+}
+
+#[derive(Error, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ParserErrorKind {
+    #[error("unknown tag")]
     InvalidTag,
-    #[error("Not enough data")]
-    MissingData,
-    #[error("Data isn't UTF8")]
-    NotUTF8,
+    /// The input ends before the field does
+    #[error("{expected} bytes expected, {actual} left")]
+    Incomplete { expected: usize, actual: usize },
+    #[error("not UTF-8 after {valid_up_to} bytes")]
+    NotUtf8 { valid_up_to: usize },
 }
-macro_rules! packet_def {
-            ($tag:expr,$input:expr ,($($id:ident),*)) => {
-                match $tag {
-                    $($id::IDENTIFIER => $id::from_bytes($input).map(|(input,packet)| (input, Packet::$id(packet))),)*
-                    _ => Err(nom::Err::Failure(ParserError::InvalidTag)),
-                }
-            };
+
+impl ParserError {
+    fn new(kind: ParserErrorKind) -> Self {
+        Self {
+            tag: None,
+            field: None,
+            offset: 0,
+            kind,
         }
-impl<'a> FromBytes<'a> for Packet<'a> {
-    fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Packet<'a>, ParserError> {
-        let (_, tag) =
-            bytes::take(3usize)(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::InvalidTag)
-            })?;
-        let tag = {
-            let mut tmp = [0; 3];
-            tmp.clone_from_slice(tag);
-            tmp
-        };
-
-        let (input, packet) = packet_def!(
-            tag,
-            input,
-            (
-                ClientRegistrationRequest,
-                ClientSendMessage,
-                HeartBeatSend,
-                HeartBeatRequest,
-                ServerBroadcastMessage,
-                ServerRegistrationConfirmation,
-                ClientRegistrationEnd
-            )
-        )?;
-
-        Ok((input, packet))
+    }
+
+    /// Whether more input could make the packet valid, otherwise the packet is malformed.
+    ///
+    /// A packet can only be incomplete when reading it out of a frame, the frame knows its length.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self.kind, ParserErrorKind::Incomplete { .. })
     }
 }
 
-impl<'a> FromBytes<'a> for ClientRegistrationRequest<'a> {
-    fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
-        let (input, _) = nom::bytes::complete::tag(&Self::IDENTIFIER)(input).map_err(
-            |_: nom::Err<nom::error::Error<_>>| nom::Err::Failure(ParserError::InvalidTag),
-        )?;
-        let (input, username_len) =
-            nom::number::complete::be_u8(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, username_bytes) =
-            bytes::take(username_len)(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let username = std::str::from_utf8(username_bytes)
-            .map_err(|_| nom::Err::Failure(ParserError::NotUTF8))?;
-
-        Ok((
-            input,
-            ClientRegistrationRequest {
-                username,
-                username_len,
-            },
-        ))
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(tag) = &self.tag {
+            write!(f, "`{}` packet, ", String::from_utf8_lossy(tag))?;
+        }
+        match self.field {
+            Some(field) => write!(f, "`{}` at byte {}: {}", field, self.offset, self.kind),
+            None => write!(f, "tag: {}", self.kind),
+        }
     }
 }
-impl<'a> FromBytes<'a> for ClientRegistrationEnd {
-    fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
-        let (input, _) = nom::bytes::complete::tag(&Self::IDENTIFIER)(input).map_err(
-            |_: nom::Err<nom::error::Error<_>>| nom::Err::Failure(ParserError::InvalidTag),
-        )?;
-        let (input, client_id) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, magic) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        Ok((input, ClientRegistrationEnd { client_id, magic }))
+
+impl std::error::Error for ParserError {}
+
+// The parsers below are used by the code of `#[derive(Packet)]`
+
+/// A field read the same way in every packet, a number or an array of bytes
+pub(crate) trait ReadField<'a>: Sized {
+    /// Number of bytes the field is sent in
+    const LEN: usize;
+
+    fn read(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError>;
+}
+
+macro_rules! read_number {
+    ($($number:ty),*) => {
+        $(
+            impl<'a> ReadField<'a> for $number {
+                const LEN: usize = std::mem::size_of::<$number>();
+
+                fn read(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
+                    let (input, bytes) = take(input, Self::LEN)?;
+                    let mut array = [0u8; std::mem::size_of::<$number>()];
+                    array.copy_from_slice(bytes);
+                    Ok((input, <$number>::from_be_bytes(array)))
+                }
+            }
+        )*
+    };
+}
+
+read_number!(u8, u16, u32, u64);
+
+impl<'a, const N: usize> ReadField<'a> for [u8; N] {
+    const LEN: usize = N;
+
+    fn read(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
+        let (input, bytes) = take(input, N)?;
+        let mut array = [0u8; N];
+        array.copy_from_slice(bytes);
+        Ok((input, array))
     }
 }
 
-impl<'a> FromBytes<'a> for ClientSendMessage<'a> {
-    fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
-        let (input, _) = nom::bytes::complete::tag(&Self::IDENTIFIER)(input).map_err(
-            |_: nom::Err<nom::error::Error<_>>| nom::Err::Failure(ParserError::InvalidTag),
-        )?;
-        let (input, client_id) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, magic) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, message_len) =
-            nom::number::complete::be_u16(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, message_bytes) =
-            bytes::take(message_len)(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let message = std::str::from_utf8(message_bytes)
-            .map_err(|_| nom::Err::Failure(ParserError::NotUTF8))?;
-
-        Ok((
-            input,
-            ClientSendMessage {
-                client_id,
-                magic,
-                message_len,
-                message,
+// The first `len` bytes of the input
+fn take(input: &[u8], len: usize) -> IResult<&[u8], &[u8], ParserError> {
+    if input.len() < len {
+        return Err(nom::Err::Failure(ParserError::new(
+            ParserErrorKind::Incomplete {
+                expected: len,
+                actual: input.len(),
             },
-        ))
+        )));
     }
+    Ok((&input[len..], &input[..len]))
 }
-impl<'a> FromBytes<'a> for HeartBeatSend {
-    fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
-        let (input, _) = nom::bytes::complete::tag(&Self::IDENTIFIER)(input).map_err(
-            |_: nom::Err<nom::error::Error<_>>| nom::Err::Failure(ParserError::InvalidTag),
-        )?;
-        let (input, client_id) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, magic) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        Ok((input, HeartBeatSend { client_id, magic }))
-    }
+
+/// The tag of the packet starting the input
+pub(crate) fn peek_tag(input: &[u8]) -> Result<[u8; 3], nom::Err<ParserError>> {
+    let (_, tag) = take(input, 3)?;
+    Ok([tag[0], tag[1], tag[2]])
 }
 
-impl<'a> FromBytes<'a> for HeartBeatRequest {
-    fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
-        let (input, _) = nom::bytes::complete::tag(&Self::IDENTIFIER)(input).map_err(
-            |_: nom::Err<nom::error::Error<_>>| nom::Err::Failure(ParserError::InvalidTag),
-        )?;
-        Ok((input, HeartBeatRequest {}))
+/// Skip the tag of the packet, it must be `identifier`
+pub(crate) fn tag<'a>(identifier: &[u8; 3], input: &'a [u8]) -> IResult<&'a [u8], (), ParserError> {
+    let tag = peek_tag(input)?;
+    if &tag != identifier {
+        return Err(invalid_tag(tag));
     }
+    Ok((&input[3..], ()))
 }
-impl<'a> FromBytes<'a> for ServerBroadcastMessage<'a> {
-    fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
-        let (input, _) = nom::bytes::complete::tag(&Self::IDENTIFIER)(input).map_err(
-            |_: nom::Err<nom::error::Error<_>>| nom::Err::Failure(ParserError::InvalidTag),
-        )?;
-
-        let (input, user_id) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, username_len) =
-            nom::number::complete::be_u8(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, username_bytes) =
-            bytes::take(username_len)(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let username = std::str::from_utf8(username_bytes)
-            .map_err(|_| nom::Err::Failure(ParserError::NotUTF8))?;
-        let (input, message_len) =
-            nom::number::complete::be_u16(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let (input, message_bytes) =
-            bytes::take(message_len)(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-        let message = std::str::from_utf8(message_bytes)
-            .map_err(|_| nom::Err::Failure(ParserError::NotUTF8))?;
-        Ok((
-            input,
-            ServerBroadcastMessage {
-                user_id,
-                username_len,
-                username,
-                message_len,
-                message,
-            },
-        ))
-    }
+
+/// No packet is tagged `tag`, or not the one expected
+pub(crate) fn invalid_tag(tag: [u8; 3]) -> nom::Err<ParserError> {
+    nom::Err::Failure(ParserError {
+        tag: Some(tag),
+        ..ParserError::new(ParserErrorKind::InvalidTag)
+    })
 }
 
-impl<'a> FromBytes<'a> for ServerRegistrationConfirmation {
-    fn from_bytes(input: &'a [u8]) -> IResult<&'a [u8], Self, ParserError> {
-        let (input, _) = nom::bytes::complete::tag(&Self::IDENTIFIER)(input).map_err(
-            |_: nom::Err<nom::error::Error<_>>| nom::Err::Failure(ParserError::InvalidTag),
-        )?;
-        let (input, client_id) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-
-        let (input, magic) =
-            nom::number::complete::be_u32(input).map_err(|_: nom::Err<nom::error::Error<_>>| {
-                nom::Err::Failure(ParserError::MissingData)
-            })?;
-
-        Ok((input, ServerRegistrationConfirmation { client_id, magic }))
+/// A string of `len` bytes
+pub(crate) fn string(input: &[u8], len: usize) -> IResult<&[u8], &str, ParserError> {
+    let (input, bytes) = take(input, len)?;
+    let string = std::str::from_utf8(bytes).map_err(|e| {
+        nom::Err::Failure(ParserError::new(ParserErrorKind::NotUtf8 {
+            valid_up_to: e.valid_up_to(),
+        }))
+    })?;
+    Ok((input, string))
+}
+
+/// A list of `len` items sent one after the other
+pub(crate) fn list<'a, T: FromBytes<'a>>(
+    input: &'a [u8],
+    len: usize,
+) -> IResult<&'a [u8], Vec<T>, ParserError> {
+    // don't trust the length for the allocation, every item takes a few bytes
+    let mut items = Vec::with_capacity(len.min(input.len() / T::MIN_LEN.max(1)));
+    let start = input;
+    let mut input = input;
+    for _ in 0..len {
+        let (rest, item) = T::from_bytes(input).map_err(|e| within(e, None, None, start, input))?;
+        items.push(item);
+        input = rest;
     }
+    Ok((input, items))
+}
+
+/// Put the error of a field read at `input` in the context of the packet starting at `start`
+pub(crate) fn within(
+    e: nom::Err<ParserError>,
+    tag: Option<[u8; 3]>,
+    field: Option<&'static str>,
+    start: &[u8],
+    input: &[u8],
+) -> nom::Err<ParserError> {
+    e.map(|mut e| {
+        e.offset += start.len() - input.len();
+        // the innermost field is the most precise, the outermost tag is the packet
+        e.field = e.field.or(field);
+        e.tag = tag.or(e.tag);
+        e
+    })
 }
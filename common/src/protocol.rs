@@ -0,0 +1,167 @@
+use std::ops::{BitAnd, BitOr};
+
+/// Version of the protocol spoken by this crate
+pub const PROTOCOL_VERSION: u16 = 4;
+/// Oldest version of the protocol this crate can still speak
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+/// First version of the protocol with password accounts, see `auth`
+pub const AUTH_PROTOCOL_VERSION: u16 = 4;
+/// Room every client joins once registered
+pub const DEFAULT_ROOM: &str = "general";
+/// Usernames the client shows its own notices under, nobody can take them
+pub const RESERVED_USERNAMES: [&str; 2] = ["System", "Server"];
+
+/// Form of a username used to compare it, usernames are unique regardless of case
+pub fn username_key(username: &str) -> String {
+    username.to_lowercase()
+}
+
+/// Whether `username` is one of `RESERVED_USERNAMES`, whatever its case
+pub fn is_reserved_username(username: &str) -> bool {
+    let key = username_key(username);
+    RESERVED_USERNAMES
+        .iter()
+        .any(|reserved| username_key(reserved) == key)
+}
+
+/// Set of optional features a peer supports
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// The peer understands `ClientPrivateMessage` and `ServerPrivateMessage`
+    pub const PRIVATE_MESSAGES: Self = Self(1 << 0);
+    /// The peer understands `ClientChangeNick` and `ServerNickChanged`
+    pub const NICK_CHANGES: Self = Self(1 << 1);
+    /// Every capability implemented by this crate
+    pub const SUPPORTED: Self = Self(Self::PRIVATE_MESSAGES.0 | Self::NICK_CHANGES.0);
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NegotiationError {
+    #[error(
+        "Protocol version {0} is too old, the oldest supported version is {}",
+        MIN_PROTOCOL_VERSION
+    )]
+    TooOld(u16),
+}
+
+/// Pick the version and capabilities used with a peer announcing `version` and
+/// `capabilities`.
+///
+/// A peer newer than us is downgraded to our version, it is up to it to refuse
+/// if it can't speak it anymore.
+pub fn negotiate(
+    version: u16,
+    capabilities: Capabilities,
+) -> Result<(u16, Capabilities), NegotiationError> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(NegotiationError::TooOld(version));
+    }
+    Ok((
+        version.min(PROTOCOL_VERSION),
+        capabilities & Capabilities::SUPPORTED,
+    ))
+}
+
+/// Machine readable reason sent with `ServerError` and `ServerDisconnect`
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ReasonCode {
+    /// A code this version doesn't know about
+    Unknown = 0,
+    /// The packet isn't valid at this point of the connection
+    WrongState = 1,
+    /// The client id in the packet isn't the one of the connection
+    WrongId = 2,
+    /// The magic in the packet isn't the one of the connection
+    WrongMagic = 3,
+    /// The packet can only be sent by a server
+    ServerOnlyPacket = 4,
+    /// The packet couldn't be parsed
+    MalformedPacket = 5,
+    /// The client didn't answer the heartbeat requests
+    HeartBeatTimeout = 6,
+    /// The server is shutting down
+    ServerShutdown = 7,
+    /// The client isn't a member of the room
+    NotInRoom = 8,
+    /// The room name isn't valid
+    InvalidRoom = 9,
+    /// No connected user matches the target
+    UnknownUser = 10,
+    /// The target didn't negotiate the capability needed for the packet
+    Unsupported = 11,
+    /// The client doesn't read what it is sent fast enough
+    TooSlow = 12,
+    /// The server has as many clients as it accepts
+    ServerFull = 13,
+    /// Another connected user has the username
+    UsernameTaken = 14,
+    /// The username is reserved or breaks the rules of `validation::username`
+    InvalidUsername = 15,
+    /// The message is empty or too long once cleaned
+    InvalidMessage = 16,
+}
+
+impl ReasonCode {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_code(code: u16) -> Self {
+        use ReasonCode::*;
+        match code {
+            1 => WrongState,
+            2 => WrongId,
+            3 => WrongMagic,
+            4 => ServerOnlyPacket,
+            5 => MalformedPacket,
+            6 => HeartBeatTimeout,
+            7 => ServerShutdown,
+            8 => NotInRoom,
+            9 => InvalidRoom,
+            10 => UnknownUser,
+            11 => Unsupported,
+            12 => TooSlow,
+            13 => ServerFull,
+            14 => UsernameTaken,
+            15 => InvalidUsername,
+            16 => InvalidMessage,
+            _ => Unknown,
+        }
+    }
+}
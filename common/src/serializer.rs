@@ -1,127 +1,155 @@
 extern crate cookie_factory as cookie;
 
-use crate::{
-    ClientRegistrationEnd, ClientRegistrationRequest, ClientSendMessage, HeartBeatRequest,
-    HeartBeatSend, Packet, ServerBroadcastMessage, ServerRegistrationConfirmation,
-};
+use std::io::Write;
+
+use bytes::{BufMut, BytesMut};
+
+/// `GenError::CustomError` code of a `*_len` field that doesn't match its payload
+pub const LENGTH_MISMATCH: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum SerializeError {
+    #[error("A length field doesn't match the length of its payload")]
+    LengthMismatch,
+    #[error("The buffer is too small, {needed} bytes are needed but it has {available}")]
+    BufferTooSmall { needed: usize, available: usize },
+    #[error("The packet is too big for a frame: {0} bytes")]
+    TooBig(usize),
+    #[error("Unable to write the packet: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<cookie::GenError> for SerializeError {
+    fn from(e: cookie::GenError) -> Self {
+        match e {
+            cookie::GenError::CustomError(LENGTH_MISMATCH) => SerializeError::LengthMismatch,
+            cookie::GenError::IoError(e) => SerializeError::Io(e),
+            e => SerializeError::Io(std::io::Error::other(e)),
+        }
+    }
+}
+
 pub trait IntoBytes {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>>;
+    /// Number of bytes written by `serialize`
+    fn encoded_len(&self) -> usize;
+
+    /// Write the packet to the context, the building block of the other methods
+    fn serialize<W: Write>(&self, context: cookie::WriteContext<W>) -> cookie::GenResult<W>;
+
+    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
+        self.serialize(cookie::WriteContext::from(Vec::with_capacity(
+            self.encoded_len(),
+        )))
+    }
     fn unwrap_bytes(&self) -> Vec<u8> {
         self.into_bytes().unwrap().into_inner().0
     }
-}
 
-macro_rules! packet_def {
-    ($input:expr, ($($packet:ident),*) ) => {
-        match $input {
-            $(Packet::$packet(p) => p.into_bytes(),)*
+    /// Write the packet to `writer` without allocating, returns the number of bytes written.
+    ///
+    /// The writer may have received part of the packet when an error is returned.
+    fn write_to<W: Write>(&self, writer: W) -> Result<usize, SerializeError> {
+        let context = self.serialize(cookie::WriteContext::from(writer))?;
+        Ok(context.position as usize)
+    }
+
+    /// Write the packet at the start of `buffer`, returns the number of bytes written
+    fn write_to_slice(&self, buffer: &mut [u8]) -> Result<usize, SerializeError> {
+        let needed = self.encoded_len();
+        if needed > buffer.len() {
+            return Err(SerializeError::BufferTooSmall {
+                needed,
+                available: buffer.len(),
+            });
         }
-    };
-}
+        self.write_to(buffer)
+    }
 
-impl<'a> IntoBytes for Packet<'a> {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
-        packet_def!(
-            self,
-            (
-                ClientSendMessage,
-                ClientRegistrationRequest,
-                HeartBeatSend,
-                HeartBeatRequest,
-                ServerBroadcastMessage,
-                ServerRegistrationConfirmation,
-                ClientRegistrationEnd
-            )
-        )
+    /// Append the packet to `buffer`, returns the number of bytes written.
+    ///
+    /// `buffer` is left as it was when an error is returned.
+    fn write_to_bytes_mut(&self, buffer: &mut BytesMut) -> Result<usize, SerializeError> {
+        let start = buffer.len();
+        buffer.reserve(self.encoded_len());
+        self.write_to((&mut *buffer).writer())
+            .inspect_err(|_| buffer.truncate(start))
     }
 }
-impl IntoBytes for ClientRegistrationEnd {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
-        let raw_buffer = Vec::with_capacity(3 + 4 + 4);
-        let context = cookie::WriteContext::from(raw_buffer);
-        let context = cookie::combinator::slice(&Self::IDENTIFIER)(context)?;
 
-        let context = cookie::bytes::be_u32(self.client_id)(context)?;
-        let context = cookie::bytes::be_u32(self.magic)(context)?;
-
-        Ok(context)
+// The length field of a payload, refused rather than sending a corrupt packet
+pub(crate) fn checked_len<T: Copy + Into<usize>>(
+    len: T,
+    actual: usize,
+) -> Result<T, cookie::GenError> {
+    if len.into() == actual {
+        Ok(len)
+    } else {
+        Err(cookie::GenError::CustomError(LENGTH_MISMATCH))
     }
 }
 
-impl<'a> IntoBytes for ClientSendMessage<'a> {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
-        let raw_buffer: Vec<u8> = Vec::with_capacity(3 + 4 + 4 + 2 + self.message.bytes().len());
-        let context = cookie::WriteContext::from(raw_buffer);
-        let context = cookie::combinator::slice(&Self::IDENTIFIER)(context)?;
+// The writers below are used by the code of `#[derive(Packet)]`
 
-        let context = cookie::bytes::be_u32(self.client_id)(context)?;
-        let context = cookie::bytes::be_u32(self.magic)(context)?;
-        let context = cookie::bytes::be_u16(self.message_len)(context)?;
-        let context = cookie::combinator::string(&self.message)(context)?;
+/// A field written the same way in every packet
+pub(crate) trait WriteField {
+    /// Number of bytes the field is sent in
+    fn field_len(&self) -> usize;
 
-        Ok(context)
-    }
+    fn write_field<W: Write>(&self, context: cookie::WriteContext<W>) -> cookie::GenResult<W>;
 }
-impl<'a> IntoBytes for ClientRegistrationRequest<'a> {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
-        let raw_buffer: Vec<u8> = Vec::with_capacity(3 + 1 + self.username.bytes().len());
-        let context = cookie::WriteContext::from(raw_buffer);
-        let context = cookie::combinator::slice(&Self::IDENTIFIER)(context)?;
-
-        let context = cookie::bytes::be_u8(self.username_len)(context)?;
-        let context = cookie::combinator::string(&self.username)(context)?;
 
-        Ok(context)
-    }
+macro_rules! write_number {
+    ($($number:ty => $writer:ident),*) => {
+        $(
+            impl WriteField for $number {
+                fn field_len(&self) -> usize {
+                    std::mem::size_of::<$number>()
+                }
+
+                fn write_field<W: Write>(
+                    &self,
+                    context: cookie::WriteContext<W>,
+                ) -> cookie::GenResult<W> {
+                    cookie::bytes::$writer(*self)(context)
+                }
+            }
+        )*
+    };
 }
-impl IntoBytes for HeartBeatSend {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
-        let raw_buffer: Vec<u8> = Vec::with_capacity(3 + 4 + 4);
-        let context = cookie::WriteContext::from(raw_buffer);
-        let context = cookie::combinator::slice(&Self::IDENTIFIER)(context)?;
 
-        let context = cookie::bytes::be_u32(self.client_id)(context)?;
-        let context = cookie::bytes::be_u32(self.magic)(context)?;
+write_number!(u8 => be_u8, u16 => be_u16, u32 => be_u32, u64 => be_u64);
 
-        Ok(context)
+impl<const N: usize> WriteField for [u8; N] {
+    fn field_len(&self) -> usize {
+        N
     }
-}
 
-impl IntoBytes for HeartBeatRequest {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
-        let raw_buffer: Vec<u8> = Vec::with_capacity(3);
-        let context = cookie::WriteContext::from(raw_buffer);
-        let context = cookie::combinator::slice(&Self::IDENTIFIER)(context)?;
-        Ok(context)
+    fn write_field<W: Write>(&self, context: cookie::WriteContext<W>) -> cookie::GenResult<W> {
+        cookie::combinator::slice(self)(context)
     }
 }
-impl IntoBytes for ServerRegistrationConfirmation {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
-        let raw_buffer: Vec<u8> = Vec::with_capacity(3 + 4);
-        let context = cookie::WriteContext::from(raw_buffer);
-        let context = cookie::combinator::slice(&Self::IDENTIFIER)(context)?;
 
-        let context = cookie::bytes::be_u32(self.client_id)(context)?;
-        let context = cookie::bytes::be_u32(self.magic)(context)?;
+impl WriteField for &str {
+    fn field_len(&self) -> usize {
+        self.len()
+    }
 
-        Ok(context)
+    fn write_field<W: Write>(&self, context: cookie::WriteContext<W>) -> cookie::GenResult<W> {
+        cookie::combinator::string(self)(context)
     }
 }
-impl<'a> IntoBytes for ServerBroadcastMessage<'a> {
-    fn into_bytes(&self) -> cookie::GenResult<Vec<u8>> {
-        let raw_buffer: Vec<u8> = Vec::with_capacity(
-            3 + 4 + 1 + self.username.bytes().len() + 2 + self.message.bytes().len(),
-        );
-        let context = cookie::WriteContext::from(raw_buffer);
-        let context = cookie::combinator::slice(&Self::IDENTIFIER)(context)?;
-
-        let context = cookie::bytes::be_u32(self.user_id)(context)?;
-        let context = cookie::bytes::be_u8(self.username_len)(context)?;
-        let context = cookie::combinator::string(&self.username)(context)?;
 
-        let context = cookie::bytes::be_u16(self.message_len)(context)?;
-        let context = cookie::combinator::string(&self.message)(context)?;
+/// The items one after the other, their number is sent before
+impl<T: IntoBytes> WriteField for Vec<T> {
+    fn field_len(&self) -> usize {
+        self.iter().map(IntoBytes::encoded_len).sum()
+    }
 
+    fn write_field<W: Write>(&self, context: cookie::WriteContext<W>) -> cookie::GenResult<W> {
+        let mut context = context;
+        for item in self {
+            context = item.serialize(context)?;
+        }
         Ok(context)
     }
 }
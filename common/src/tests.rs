@@ -1,16 +1,58 @@
 #![allow(non_snake_case)]
 use crate::parser::FromBytes;
-use crate::serializer::IntoBytes;
+use crate::serializer::{IntoBytes, SerializeError};
 use crate::*;
 /*
 Client Registration Request         (crr):
-    => b"crr" + username.len() + username;
+    => b"crr" + protocol_version + capabilities + username.len() + username;
 Server Registration Confirmation    (src):
-    => b"src" + clientID + Magic;
+    => b"src" + clientID + Magic + protocol_version + capabilities;
+Server Registration Refusal         (srf):
+    => b"srf" + server_version + min_version + reason.len() + reason;
+Server Auth Challenge               (sac):
+    => b"sac" + salt[16] + iterations + nonce[32];
+Client Auth Response                (cau):
+    => b"cau" + proof[32];
 Client Send Message                 (csm):
-    => b"csm" + clientID + magic + message.len() + message;
+    => b"csm" + clientID + magic + room.len() + room + message.len() + message;
+Client Quit                         (cqt):
+    => b"cqt" + clientID + magic + message.len() + message;
+Client Join Room                    (cjr):
+    => b"cjr" + clientID + magic + room.len() + room;
+Client Part Room                    (cpr):
+    => b"cpr" + clientID + magic + room.len() + room;
+Client Private Message              (cpm):
+    => b"cpm" + clientID + magic + targetID + target.len() + target + message.len() + message;
+Client Roster Request               (cro):
+    => b"cro" + clientID + magic;
+Client History Request              (chr):
+    => b"chr" + clientID + magic + room.len() + room + before + limit;
+Client Change Nick                  (ccn):
+    => b"ccn" + clientID + magic + username.len() + username;
+Server Error                        (ser):
+    => b"ser" + code + message.len() + message;
+Server Disconnect                   (sdc):
+    => b"sdc" + code + message.len() + message;
+Server User Left                    (sul):
+    => b"sul" + userID + username.len() + username + message.len() + message;
 Server Broadcast Message            (sbm):
-    => b"sbm" + username.len() + username +message.len() + message;
+    => b"sbm" + messageID + timestamp + userID + room.len() + room + username.len() + username + message.len() + message;
+Server Room Joined                  (srj):
+    => b"srj" + userID + username.len() + username + room.len() + room;
+Server Room Parted                  (srp):
+    => b"srp" + userID + username.len() + username + room.len() + room;
+Server User Joined                  (suj):
+    => b"suj" + userID + username.len() + username;
+Server Roster                       (sro):
+    => b"sro" + users.len() + (userID + username.len() + username) * users.len();
+Server History                      (shs):
+    => b"shs" + room.len() + room + messages.len() + sbm_packet * messages.len();
+Server Private Message              (spm):
+    => b"spm" + userID + username.len() + username + message.len() + message;
+Server Message Of The Day           (smo):
+    => b"smo" + message.len() + message;
+Server Nick Changed                 (snc):
+    => b"snc" + userID + old_username.len() + old_username + username.len() + username;
 Heart Beat Request                  (hbr):
     => b"hbr"
 Heart Beat Send                     (hbs):
@@ -22,12 +64,16 @@ mod parse {
     #[test]
     fn ClientSendMessage() {
         assert_eq!(
-            ClientSendMessage::from_bytes(b"csm\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x06AZERTY")
-                .unwrap()
-                .1,
+            ClientSendMessage::from_bytes(
+                b"csm\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev\x00\x06AZERTY"
+            )
+            .unwrap()
+            .1,
             ClientSendMessage {
                 client_id: 0x000000FF,
                 magic: 0x0000FF00,
+                room_len: 3,
+                room: "dev",
                 message_len: 6,
                 message: "AZERTY"
             }
@@ -37,10 +83,12 @@ mod parse {
     #[test]
     fn ClientRegistrationRequest() {
         assert_eq!(
-            ClientRegistrationRequest::from_bytes(b"crr\x04Maix")
+            ClientRegistrationRequest::from_bytes(b"crr\x00\x01\x00\x00\x00\x03\x04Maix")
                 .unwrap()
                 .1,
             ClientRegistrationRequest {
+                protocol_version: 1,
+                capabilities: 3,
                 username_len: 4,
                 username: "Maix"
             }
@@ -61,12 +109,90 @@ mod parse {
     #[test]
     fn ServerRegistrationConfirmation() {
         assert_eq!(
-            ServerRegistrationConfirmation::from_bytes(b"src\x00\x00\x00\xFF\x00\x00\xFF\x00")
+            ServerRegistrationConfirmation::from_bytes(
+                b"src\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x01\x00\x00\x00\x03"
+            )
+            .unwrap()
+            .1,
+            ServerRegistrationConfirmation {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                protocol_version: 1,
+                capabilities: 3,
+            }
+        )
+    }
+
+    #[test]
+    fn ServerRegistrationRefusal() {
+        assert_eq!(
+            ServerRegistrationRefusal::from_bytes(b"srf\x00\x02\x00\x02\x00\x03Old")
                 .unwrap()
                 .1,
-            ServerRegistrationConfirmation {
+            ServerRegistrationRefusal {
+                server_version: 2,
+                min_version: 2,
+                reason_len: 3,
+                reason: "Old"
+            }
+        )
+    }
+
+    #[test]
+    fn ClientQuit() {
+        assert_eq!(
+            ClientQuit::from_bytes(b"cqt\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x04Home")
+                .unwrap()
+                .1,
+            ClientQuit {
                 client_id: 0x000000FF,
                 magic: 0x0000FF00,
+                message_len: 4,
+                message: "Home"
+            }
+        )
+    }
+
+    #[test]
+    fn ServerUserLeft() {
+        assert_eq!(
+            ServerUserLeft::from_bytes(b"sul\xFF\xDD\x00\xFF\x04Maix\x00\x00")
+                .unwrap()
+                .1,
+            ServerUserLeft {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+                message_len: 0,
+                message: ""
+            }
+        )
+    }
+
+    #[test]
+    fn ServerError() {
+        assert_eq!(
+            ServerError::from_bytes(b"ser\x00\x05\x00\x03Bad")
+                .unwrap()
+                .1,
+            ServerError {
+                code: 5,
+                message_len: 3,
+                message: "Bad"
+            }
+        )
+    }
+
+    #[test]
+    fn ServerDisconnect() {
+        assert_eq!(
+            ServerDisconnect::from_bytes(b"sdc\x00\x07\x00\x03Bye")
+                .unwrap()
+                .1,
+            ServerDisconnect {
+                code: 7,
+                message_len: 3,
+                message: "Bye"
             }
         )
     }
@@ -75,12 +201,16 @@ mod parse {
     fn ServerBroadcastMessage() {
         assert_eq!(
             ServerBroadcastMessage::from_bytes(
-                b"sbm\xFF\xDD\x00\xFF\x04Maix\x00\x0FJeSuisUneBanane"
+                b"sbm\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x00\x01\x75\x3F\x8A\x2B\x00\xFF\xDD\x00\xFF\x03dev\x04Maix\x00\x0FJeSuisUneBanane"
             )
             .unwrap()
             .1,
             ServerBroadcastMessage {
+                message_id: 42,
+                timestamp: 0x00000175_3F8A2B00,
                 user_id: 0xFFDD00FF,
+                room_len: 3,
+                room: "dev",
                 username_len: 0x04,
                 username: "Maix",
                 message_len: 0x0F,
@@ -89,6 +219,247 @@ mod parse {
         )
     }
 
+    #[test]
+    fn ClientJoinRoom() {
+        assert_eq!(
+            ClientJoinRoom::from_bytes(b"cjr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev")
+                .unwrap()
+                .1,
+            ClientJoinRoom {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                room_len: 3,
+                room: "dev",
+            }
+        )
+    }
+
+    #[test]
+    fn ClientPartRoom() {
+        assert_eq!(
+            ClientPartRoom::from_bytes(b"cpr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev")
+                .unwrap()
+                .1,
+            ClientPartRoom {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                room_len: 3,
+                room: "dev",
+            }
+        )
+    }
+
+    #[test]
+    fn ServerRoomJoined() {
+        assert_eq!(
+            ServerRoomJoined::from_bytes(b"srj\xFF\xDD\x00\xFF\x04Maix\x03dev")
+                .unwrap()
+                .1,
+            ServerRoomJoined {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+                room_len: 3,
+                room: "dev",
+            }
+        )
+    }
+
+    #[test]
+    fn ServerRoomParted() {
+        assert_eq!(
+            ServerRoomParted::from_bytes(b"srp\xFF\xDD\x00\xFF\x04Maix\x03dev")
+                .unwrap()
+                .1,
+            ServerRoomParted {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+                room_len: 3,
+                room: "dev",
+            }
+        )
+    }
+
+    #[test]
+    fn ClientPrivateMessage() {
+        assert_eq!(
+            ClientPrivateMessage::from_bytes(
+                b"cpm\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x00\x00\x00\x04Maix\x00\x05hello"
+            )
+            .unwrap()
+            .1,
+            ClientPrivateMessage {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                target_id: 0,
+                target_len: 4,
+                target: "Maix",
+                message_len: 5,
+                message: "hello",
+            }
+        )
+    }
+
+    #[test]
+    fn ServerPrivateMessage() {
+        assert_eq!(
+            ServerPrivateMessage::from_bytes(b"spm\xFF\xDD\x00\xFF\x04Maix\x00\x05hello")
+                .unwrap()
+                .1,
+            ServerPrivateMessage {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+                message_len: 5,
+                message: "hello",
+            }
+        )
+    }
+
+    #[test]
+    fn ClientRosterRequest() {
+        assert_eq!(
+            ClientRosterRequest::from_bytes(b"cro\x00\x00\x00\xFF\x00\x00\xFF\x00")
+                .unwrap()
+                .1,
+            ClientRosterRequest {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+            }
+        )
+    }
+
+    #[test]
+    fn ServerUserJoined() {
+        assert_eq!(
+            ServerUserJoined::from_bytes(b"suj\xFF\xDD\x00\xFF\x04Maix")
+                .unwrap()
+                .1,
+            ServerUserJoined {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+            }
+        )
+    }
+
+    #[test]
+    fn ServerRoster() {
+        assert_eq!(
+            ServerRoster::from_bytes(b"sro\x00\x02\xFF\xDD\x00\xFF\x04Maix\x00\x00\x00\x01\x03Bob")
+                .unwrap()
+                .1,
+            ServerRoster {
+                users_len: 2,
+                users: vec![
+                    RosterEntry {
+                        user_id: 0xFFDD00FF,
+                        username_len: 4,
+                        username: "Maix"
+                    },
+                    RosterEntry {
+                        user_id: 1,
+                        username_len: 3,
+                        username: "Bob"
+                    }
+                ],
+            }
+        )
+    }
+
+    #[test]
+    fn ClientHistoryRequest() {
+        assert_eq!(
+            ClientHistoryRequest::from_bytes(b"chr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x32").unwrap().1,
+            ClientHistoryRequest {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                room_len: 3,
+                room: "dev",
+                before: 42,
+                limit: 50,
+            }
+        )
+    }
+
+    #[test]
+    fn ServerHistory() {
+        assert_eq!(
+            ServerHistory::from_bytes(b"shs\x03dev\x00\x01sbm\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x00\x01\x75\x3F\x8A\x2B\x00\xFF\xDD\x00\xFF\x03dev\x04Maix\x00\x02hi").unwrap().1,
+            ServerHistory {
+                room_len: 3,
+                room: "dev",
+                messages_len: 1,
+                messages: vec![ServerBroadcastMessage { message_id: 42, timestamp: 0x00000175_3F8A2B00, user_id: 0xFFDD00FF, room_len: 3, room: "dev", username_len: 4, username: "Maix", message_len: 2, message: "hi" }],
+            }
+        )
+    }
+
+    #[test]
+    fn ServerMotd() {
+        assert_eq!(
+            ServerMotd::from_bytes(b"smo\x00\x05hello").unwrap().1,
+            ServerMotd {
+                message_len: 5,
+                message: "hello",
+            }
+        )
+    }
+
+    #[test]
+    fn ServerAuthChallenge() {
+        assert_eq!(
+            ServerAuthChallenge::from_bytes(b"sac\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x00\x01\x86\xA0\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22").unwrap().1,
+            ServerAuthChallenge {
+                salt: [0x11; 16],
+                iterations: 100000,
+                nonce: [0x22; 32],
+            }
+        )
+    }
+
+    #[test]
+    fn ClientAuthResponse() {
+        assert_eq!(
+            ClientAuthResponse::from_bytes(b"cau\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33").unwrap().1,
+            ClientAuthResponse {
+                proof: [0x33; 32],
+            }
+        )
+    }
+
+    #[test]
+    fn ClientChangeNick() {
+        assert_eq!(
+            ClientChangeNick::from_bytes(b"ccn\xFF\xDD\x00\xFF\x00\x00\x00\x01\x04Maix")
+                .unwrap()
+                .1,
+            ClientChangeNick {
+                client_id: 0xFFDD00FF,
+                magic: 0x00000001,
+                username_len: 4,
+                username: "Maix",
+            }
+        )
+    }
+
+    #[test]
+    fn ServerNickChanged() {
+        assert_eq!(
+            ServerNickChanged::from_bytes(b"snc\xFF\xDD\x00\xFF\x04Maix\x05Ahmed")
+                .unwrap()
+                .1,
+            ServerNickChanged {
+                user_id: 0xFFDD00FF,
+                old_username_len: 4,
+                old_username: "Maix",
+                username_len: 5,
+                username: "Ahmed",
+            }
+        )
+    }
+
     #[test]
     fn HeartBeatRequest() {
         assert_eq!(
@@ -107,22 +478,26 @@ mod serialize {
             ClientSendMessage {
                 client_id: 0x000000FF,
                 magic: 0x0000FF00,
+                room_len: 3,
+                room: "dev",
                 message_len: 6,
                 message: "AZERTY"
             }
             .unwrap_bytes(),
-            b"csm\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x06AZERTY"
+            b"csm\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev\x00\x06AZERTY"
         )
     }
     #[test]
     fn ClientRegistrationRequest() {
         assert_eq!(
             ClientRegistrationRequest {
+                protocol_version: 1,
+                capabilities: 3,
                 username_len: 4,
                 username: "Maix"
             }
             .unwrap_bytes(),
-            b"crr\x04Maix"
+            b"crr\x00\x01\x00\x00\x00\x03\x04Maix"
         )
     }
 
@@ -144,9 +519,80 @@ mod serialize {
             ServerRegistrationConfirmation {
                 client_id: 0x000000FF,
                 magic: 0x0000FF00,
+                protocol_version: 1,
+                capabilities: 3,
+            }
+            .unwrap_bytes(),
+            b"src\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x01\x00\x00\x00\x03"
+        )
+    }
+
+    #[test]
+    fn ServerRegistrationRefusal() {
+        assert_eq!(
+            ServerRegistrationRefusal {
+                server_version: 2,
+                min_version: 2,
+                reason_len: 3,
+                reason: "Old"
+            }
+            .unwrap_bytes(),
+            b"srf\x00\x02\x00\x02\x00\x03Old"
+        )
+    }
+
+    #[test]
+    fn ClientQuit() {
+        assert_eq!(
+            ClientQuit {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                message_len: 4,
+                message: "Home"
+            }
+            .unwrap_bytes(),
+            b"cqt\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x04Home"
+        )
+    }
+
+    #[test]
+    fn ServerUserLeft() {
+        assert_eq!(
+            ServerUserLeft {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+                message_len: 0,
+                message: ""
+            }
+            .unwrap_bytes(),
+            b"sul\xFF\xDD\x00\xFF\x04Maix\x00\x00"
+        )
+    }
+
+    #[test]
+    fn ServerError() {
+        assert_eq!(
+            ServerError {
+                code: 5,
+                message_len: 3,
+                message: "Bad"
+            }
+            .unwrap_bytes(),
+            b"ser\x00\x05\x00\x03Bad"
+        )
+    }
+
+    #[test]
+    fn ServerDisconnect() {
+        assert_eq!(
+            ServerDisconnect {
+                code: 7,
+                message_len: 3,
+                message: "Bye"
             }
             .unwrap_bytes(),
-            b"src\x00\x00\x00\xFF\x00\x00\xFF\x00"
+            b"sdc\x00\x07\x00\x03Bye"
         )
     }
 
@@ -154,19 +600,1154 @@ mod serialize {
     fn ServerBroadcastMessage() {
         assert_eq!(
             ServerBroadcastMessage {
+                message_id: 42,
+                timestamp: 0x00000175_3F8A2B00,
                 user_id: 0xFFDD00FF,
+                room_len: 3,
+                room: "dev",
                 username_len: 0x04,
                 username: "Maix",
                 message_len: 0x0F,
                 message: "JeSuisUneBanane"
             }
             .unwrap_bytes(),
-            b"sbm\xFF\xDD\x00\xFF\x04Maix\x00\x0FJeSuisUneBanane"
+            b"sbm\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x00\x01\x75\x3F\x8A\x2B\x00\xFF\xDD\x00\xFF\x03dev\x04Maix\x00\x0FJeSuisUneBanane"
         )
     }
 
     #[test]
-    fn HeartBeatRequest() {
-        assert_eq!(HeartBeatRequest {}.unwrap_bytes(), b"hbr")
+    fn ClientJoinRoom() {
+        assert_eq!(
+            ClientJoinRoom {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                room_len: 3,
+                room: "dev",
+            }
+            .unwrap_bytes(),
+            b"cjr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev"
+        )
+    }
+
+    #[test]
+    fn ClientPartRoom() {
+        assert_eq!(
+            ClientPartRoom {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                room_len: 3,
+                room: "dev",
+            }
+            .unwrap_bytes(),
+            b"cpr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev"
+        )
+    }
+
+    #[test]
+    fn ServerRoomJoined() {
+        assert_eq!(
+            ServerRoomJoined {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+                room_len: 3,
+                room: "dev",
+            }
+            .unwrap_bytes(),
+            b"srj\xFF\xDD\x00\xFF\x04Maix\x03dev"
+        )
+    }
+
+    #[test]
+    fn ServerRoomParted() {
+        assert_eq!(
+            ServerRoomParted {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+                room_len: 3,
+                room: "dev",
+            }
+            .unwrap_bytes(),
+            b"srp\xFF\xDD\x00\xFF\x04Maix\x03dev"
+        )
+    }
+
+    #[test]
+    fn ClientPrivateMessage() {
+        assert_eq!(
+            ClientPrivateMessage {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                target_id: 0,
+                target_len: 4,
+                target: "Maix",
+                message_len: 5,
+                message: "hello",
+            }
+            .unwrap_bytes(),
+            b"cpm\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x00\x00\x00\x04Maix\x00\x05hello"
+        )
+    }
+
+    #[test]
+    fn ServerPrivateMessage() {
+        assert_eq!(
+            ServerPrivateMessage {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+                message_len: 5,
+                message: "hello",
+            }
+            .unwrap_bytes(),
+            b"spm\xFF\xDD\x00\xFF\x04Maix\x00\x05hello"
+        )
+    }
+
+    #[test]
+    fn ClientRosterRequest() {
+        assert_eq!(
+            ClientRosterRequest {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+            }
+            .unwrap_bytes(),
+            b"cro\x00\x00\x00\xFF\x00\x00\xFF\x00"
+        )
+    }
+
+    #[test]
+    fn ServerUserJoined() {
+        assert_eq!(
+            ServerUserJoined {
+                user_id: 0xFFDD00FF,
+                username_len: 4,
+                username: "Maix",
+            }
+            .unwrap_bytes(),
+            b"suj\xFF\xDD\x00\xFF\x04Maix"
+        )
+    }
+
+    #[test]
+    fn ServerRoster() {
+        assert_eq!(
+            ServerRoster {
+                users_len: 2,
+                users: vec![
+                    RosterEntry {
+                        user_id: 0xFFDD00FF,
+                        username_len: 4,
+                        username: "Maix"
+                    },
+                    RosterEntry {
+                        user_id: 1,
+                        username_len: 3,
+                        username: "Bob"
+                    }
+                ],
+            }
+            .unwrap_bytes(),
+            b"sro\x00\x02\xFF\xDD\x00\xFF\x04Maix\x00\x00\x00\x01\x03Bob"
+        )
+    }
+
+    #[test]
+    fn ClientHistoryRequest() {
+        assert_eq!(
+            ClientHistoryRequest {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                room_len: 3,
+                room: "dev",
+                before: 42,
+                limit: 50,
+            }
+            .unwrap_bytes(),
+            b"chr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x32"
+        )
+    }
+
+    #[test]
+    fn ServerHistory() {
+        assert_eq!(
+            ServerHistory {
+                room_len: 3,
+                room: "dev",
+                messages_len: 1,
+                messages: vec![ServerBroadcastMessage { message_id: 42, timestamp: 0x00000175_3F8A2B00, user_id: 0xFFDD00FF, room_len: 3, room: "dev", username_len: 4, username: "Maix", message_len: 2, message: "hi" }],
+            }
+            .unwrap_bytes(),
+            b"shs\x03dev\x00\x01sbm\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x00\x01\x75\x3F\x8A\x2B\x00\xFF\xDD\x00\xFF\x03dev\x04Maix\x00\x02hi"
+        )
+    }
+
+    #[test]
+    fn ServerMotd() {
+        assert_eq!(
+            ServerMotd {
+                message_len: 5,
+                message: "hello",
+            }
+            .unwrap_bytes(),
+            b"smo\x00\x05hello"
+        )
+    }
+
+    #[test]
+    fn ServerAuthChallenge() {
+        assert_eq!(
+            ServerAuthChallenge {
+                salt: [0x11; 16],
+                iterations: 100000,
+                nonce: [0x22; 32],
+            }
+            .unwrap_bytes(),
+            b"sac\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x00\x01\x86\xA0\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22"
+        )
+    }
+
+    #[test]
+    fn ClientAuthResponse() {
+        assert_eq!(
+            ClientAuthResponse {
+                proof: [0x33; 32],
+            }
+            .unwrap_bytes(),
+            b"cau\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33"
+        )
+    }
+
+    #[test]
+    fn ClientChangeNick() {
+        assert_eq!(
+            ClientChangeNick {
+                client_id: 0xFFDD00FF,
+                magic: 0x00000001,
+                username_len: 4,
+                username: "Maix",
+            }
+            .unwrap_bytes(),
+            b"ccn\xFF\xDD\x00\xFF\x00\x00\x00\x01\x04Maix"
+        )
+    }
+
+    #[test]
+    fn ServerNickChanged() {
+        assert_eq!(
+            ServerNickChanged {
+                user_id: 0xFFDD00FF,
+                old_username_len: 4,
+                old_username: "Maix",
+                username_len: 5,
+                username: "Ahmed",
+            }
+            .unwrap_bytes(),
+            b"snc\xFF\xDD\x00\xFF\x04Maix\x05Ahmed"
+        )
+    }
+
+    #[test]
+    fn HeartBeatRequest() {
+        assert_eq!(HeartBeatRequest {}.unwrap_bytes(), b"hbr")
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let packet = ClientSendMessage {
+            client_id: 0x000000FF,
+            magic: 0xFF000000,
+            room_len: 7,
+            room: "general",
+            message_len: 6,
+            message: "hello",
+        };
+        match packet.into_bytes() {
+            Err(cookie_factory::GenError::CustomError(code)) => {
+                assert_eq!(code, crate::serializer::LENGTH_MISMATCH)
+            }
+            other => panic!("unexpected {:?}", other.map(|c| c.into_inner().0)),
+        }
+        // A list is checked too
+        let packet = ServerRoster {
+            users_len: 1,
+            users: vec![],
+        };
+        assert!(packet.into_bytes().is_err());
+    }
+
+    #[test]
+    fn new() {
+        let packet = ClientSendMessage::new(0x000000FF, 0xFF000000, "general", "hello").unwrap();
+        assert_eq!(packet.room_len, 7);
+        assert_eq!(packet.message_len, 5);
+        assert!(packet.into_bytes().is_ok());
+
+        let user = RosterEntry::new(0xFFDD00FF, "Maix").unwrap();
+        assert_eq!(ServerRoster::new(vec![user]).unwrap().users_len, 1);
+
+        let long = "a".repeat(256);
+        assert_eq!(
+            ClientRegistrationRequest::new(4, 0, &long),
+            Err(LengthError {
+                field: "username",
+                len: 256,
+                max: 255
+            })
+        );
+        let long = "a".repeat(65536);
+        assert_eq!(
+            ServerMotd::new(&long).unwrap_err().to_string(),
+            "`message` has a length of 65536, at most 65535 fits"
+        );
+    }
+
+    // One packet of every kind, as encoded by the tests above
+    pub(super) const PACKETS: &[&[u8]] = &[
+        b"cre\x00\x00\x00\xFF\x00\x00\xFF\x00",
+        b"csm\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev\x00\x06AZERTY",
+        b"crr\x00\x01\x00\x00\x00\x03\x04Maix",
+        b"hbs\xFF\xAA\xFF\xAA\x12\x34\x56\x78",
+        b"src\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x01\x00\x00\x00\x03",
+        b"srf\x00\x02\x00\x02\x00\x03Old",
+        b"cqt\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x04Home",
+        b"sul\xFF\xDD\x00\xFF\x04Maix\x00\x00",
+        b"ser\x00\x05\x00\x03Bad",
+        b"sdc\x00\x07\x00\x03Bye",
+        b"sbm\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x00\x01\x75\x3F\x8A\x2B\x00\xFF\xDD\x00\xFF\x03dev\x04Maix\x00\x0FJeSuisUneBanane",
+        b"cjr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev",
+        b"cpr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev",
+        b"srj\xFF\xDD\x00\xFF\x04Maix\x03dev",
+        b"srp\xFF\xDD\x00\xFF\x04Maix\x03dev",
+        b"cpm\x00\x00\x00\xFF\x00\x00\xFF\x00\x00\x00\x00\x00\x04Maix\x00\x05hello",
+        b"spm\xFF\xDD\x00\xFF\x04Maix\x00\x05hello",
+        b"cro\x00\x00\x00\xFF\x00\x00\xFF\x00",
+        b"suj\xFF\xDD\x00\xFF\x04Maix",
+        b"sro\x00\x02\xFF\xDD\x00\xFF\x04Maix\x00\x00\x00\x01\x03Bob",
+        b"chr\x00\x00\x00\xFF\x00\x00\xFF\x00\x03dev\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x32",
+        b"shs\x03dev\x00\x01sbm\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x00\x01\x75\x3F\x8A\x2B\x00\xFF\xDD\x00\xFF\x03dev\x04Maix\x00\x02hi",
+        b"smo\x00\x05hello",
+        b"sac\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x00\x01\x86\xA0\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22",
+        b"cau\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33\x33",
+        b"ccn\xFF\xDD\x00\xFF\x00\x00\x00\x01\x04Maix",
+        b"snc\xFF\xDD\x00\xFF\x04Maix\x05Ahmed",
+        b"hbr",
+    ];
+
+    #[test]
+    fn encoded_len() {
+        for raw in PACKETS {
+            let (_, packet) = Packet::from_bytes(raw).unwrap();
+            assert_eq!(packet.encoded_len(), raw.len(), "{:?}", packet);
+        }
+    }
+
+    #[test]
+    fn write_to() {
+        let mut slice = [0u8; 128];
+        let mut bytes = bytes::BytesMut::new();
+        let mut writer = Vec::new();
+        for raw in PACKETS {
+            let (_, packet) = Packet::from_bytes(raw).unwrap();
+            assert_eq!(packet.write_to_slice(&mut slice).unwrap(), raw.len());
+            assert_eq!(&slice[..raw.len()], *raw);
+            assert_eq!(packet.write_to_bytes_mut(&mut bytes).unwrap(), raw.len());
+            assert_eq!(packet.write_to(&mut writer).unwrap(), raw.len());
+        }
+        assert_eq!(&bytes[..], PACKETS.concat().as_slice());
+        assert_eq!(writer, PACKETS.concat());
+    }
+
+    #[test]
+    fn write_errors() {
+        let packet = ServerMotd::new("hello").unwrap();
+        let mut slice = [0u8; 4];
+        assert!(matches!(
+            packet.write_to_slice(&mut slice),
+            Err(SerializeError::BufferTooSmall {
+                needed: 10,
+                available: 4
+            })
+        ));
+        assert_eq!(slice, [0; 4]);
+
+        // The buffer is left as it was
+        let packet = ServerMotd {
+            message_len: 4,
+            message: "hello",
+        };
+        let mut bytes = bytes::BytesMut::from(&b"hbr"[..]);
+        assert!(matches!(
+            packet.write_to_bytes_mut(&mut bytes),
+            Err(SerializeError::LengthMismatch)
+        ));
+        assert_eq!(&bytes[..], b"hbr");
+    }
+}
+
+mod derive {
+    use super::*;
+
+    #[test]
+    fn owned() {
+        for raw in super::serialize::PACKETS {
+            let (_, packet) = Packet::from_bytes(raw).unwrap();
+            let owned = packet.into_owned();
+            assert_eq!(owned.get_identifier(), packet.get_identifier());
+            assert_eq!(&owned.get_identifier(), &raw[..3]);
+        }
+        let (_, packet) = ServerHistory::from_bytes(
+            b"shs\x03dev\x00\x01sbm\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x00\x01\x75\x3F\x8A\x2B\x00\xFF\xDD\x00\xFF\x03dev\x04Maix\x00\x02hi",
+        )
+        .unwrap();
+        assert_eq!(packet.into_owned().as_borrowed(), packet);
+    }
+
+    #[test]
+    fn min_len() {
+        assert_eq!(HeartBeatRequest::MIN_LEN, 3);
+        assert_eq!(RosterEntry::MIN_LEN, 4 + 1);
+        assert_eq!(ServerBroadcastMessage::MIN_LEN, 3 + 8 + 8 + 4 + 1 + 1 + 2);
+        assert_eq!(ServerAuthChallenge::MIN_LEN, 3 + 16 + 4 + 32);
+        // A list length isn't trusted past the bytes there are
+        assert!(matches!(
+            ServerRoster::from_bytes(b"sro\xFF\xFF\x00\x00\x00\x01\x03Bob"),
+            Err(nom::Err::Failure(e)) if e.is_incomplete()
+        ));
+    }
+}
+
+mod errors {
+    use super::*;
+    use crate::frame::{FrameDecoder, FrameError};
+    use crate::parser::{ParserError, ParserErrorKind};
+
+    fn error<'a, T: FromBytes<'a> + std::fmt::Debug>(input: &'a [u8]) -> ParserError {
+        match T::from_bytes(input) {
+            Err(nom::Err::Failure(e)) => e,
+            res => panic!("expected a failure, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn incomplete() {
+        let e = error::<ServerBroadcastMessage>(
+            b"sbm\x00\x00\x00\x00\x00\x00\x00\x2A\x00\x00\x01\x75\x3F\x8A\x2B\x00\xFF\xDD\x00\xFF\x03dev\x04Maix\x00\x0Fhi!",
+        );
+        assert_eq!(
+            e,
+            ParserError {
+                tag: Some(*b"sbm"),
+                field: Some("message"),
+                offset: 34,
+                kind: ParserErrorKind::Incomplete {
+                    expected: 15,
+                    actual: 3
+                },
+            }
+        );
+        assert!(e.is_incomplete());
+        assert_eq!(
+            e.to_string(),
+            "`sbm` packet, `message` at byte 34: 15 bytes expected, 3 left"
+        );
+
+        let e = error::<Packet>(b"sb");
+        assert_eq!((e.tag, e.field, e.offset), (None, None, 0));
+        assert_eq!(
+            e.kind,
+            ParserErrorKind::Incomplete {
+                expected: 3,
+                actual: 2
+            }
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let e = error::<ServerMotd>(b"smo\x00\x02h\xFF");
+        assert_eq!(
+            (e.tag, e.field, e.offset),
+            (Some(*b"smo"), Some("message"), 5)
+        );
+        assert_eq!(e.kind, ParserErrorKind::NotUtf8 { valid_up_to: 1 });
+        assert!(!e.is_incomplete());
+
+        let e = error::<Packet>(b"xyz");
+        assert_eq!((e.tag, e.field, e.offset), (Some(*b"xyz"), None, 0));
+        assert_eq!(e.kind, ParserErrorKind::InvalidTag);
+        assert_eq!(e.to_string(), "`xyz` packet, tag: unknown tag");
+    }
+
+    #[test]
+    fn nested() {
+        // The field of the item, from the start of the packet holding the list
+        let e = error::<ServerRoster>(b"sro\x00\x02\x00\x00\x00\x01\x03Bob\x00\x00\x00\x02\x05Al");
+        assert_eq!(
+            (e.tag, e.field, e.offset),
+            (Some(*b"sro"), Some("username"), 18)
+        );
+        assert_eq!(
+            e.kind,
+            ParserErrorKind::Incomplete {
+                expected: 5,
+                actual: 2
+            }
+        );
+
+        // A bad tag in a list is a malformed packet, not an unknown one
+        let bytes = b"shs\x03dev\x00\x01xyz";
+        let e = error::<ServerHistory>(bytes);
+        assert_eq!(
+            (e.tag, e.field, e.offset),
+            (Some(*b"shs"), Some("messages"), 9)
+        );
+        assert_eq!(e.kind, ParserErrorKind::InvalidTag);
+
+        let mut frame = b"MC".to_vec();
+        frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        frame.extend_from_slice(bytes);
+        let (_, frame) = FrameDecoder::new().decode(&frame);
+        assert!(
+            matches!(frame, Err(FrameError::Malformed(tag, e)) if &tag == b"shs" && e.field == Some("messages"))
+        );
+    }
+}
+
+mod round_trip {
+    use super::*;
+    use proptest::prelude::*;
+
+    // At most 252 bytes, for a string sent after an `u8` length
+    const SHORT: &str = ".{0,63}";
+    // For a string sent after an `u16` length
+    const LONG: &str = ".{0,300}";
+
+    fn round_trip(packet: Packet<'_>) -> Result<(), TestCaseError> {
+        let bytes = packet.unwrap_bytes();
+        prop_assert_eq!(bytes.len(), packet.encoded_len());
+        let (rest, parsed) =
+            Packet::from_bytes(&bytes).map_err(|e| TestCaseError::fail(format!("{:?}", e)))?;
+        prop_assert!(rest.is_empty());
+        prop_assert_eq!(&parsed, &packet);
+
+        // Every byte is needed, a truncated packet is incomplete
+        for len in 0..bytes.len() {
+            match Packet::from_bytes(&bytes[..len]) {
+                Err(nom::Err::Failure(e)) => prop_assert!(e.is_incomplete(), "{}", e),
+                res => prop_assert!(false, "{:?}", res),
+            }
+        }
+        Ok(())
+    }
+
+    // Nothing to generate
+    #[test]
+    fn HeartBeatRequest() -> Result<(), TestCaseError> {
+        round_trip(Packet::HeartBeatRequest(HeartBeatRequest {}))
+    }
+
+    proptest! {
+        #[test]
+        fn ClientRegistrationEnd(client_id: u32, magic: u32) {
+            round_trip(Packet::ClientRegistrationEnd(ClientRegistrationEnd { client_id, magic }))?;
+        }
+
+        #[test]
+        fn ClientRegistrationRequest(protocol_version: u16, capabilities: u32, username in SHORT) {
+            round_trip(Packet::ClientRegistrationRequest(
+                ClientRegistrationRequest::new(protocol_version, capabilities, &username).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ServerRegistrationConfirmation(
+            client_id: u32,
+            magic: u32,
+            protocol_version: u16,
+            capabilities: u32,
+        ) {
+            round_trip(Packet::ServerRegistrationConfirmation(ServerRegistrationConfirmation {
+                client_id,
+                magic,
+                protocol_version,
+                capabilities,
+            }))?;
+        }
+
+        #[test]
+        fn ServerRegistrationRefusal(server_version: u16, min_version: u16, reason in LONG) {
+            round_trip(Packet::ServerRegistrationRefusal(
+                ServerRegistrationRefusal::new(server_version, min_version, &reason).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ClientSendMessage(client_id: u32, magic: u32, room in SHORT, message in LONG) {
+            round_trip(Packet::ClientSendMessage(
+                ClientSendMessage::new(client_id, magic, &room, &message).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ServerError(code: u16, message in LONG) {
+            round_trip(Packet::ServerError(ServerError::new(code, &message).unwrap()))?;
+        }
+
+        #[test]
+        fn ServerDisconnect(code: u16, message in LONG) {
+            round_trip(Packet::ServerDisconnect(ServerDisconnect::new(code, &message).unwrap()))?;
+        }
+
+        #[test]
+        fn ClientQuit(client_id: u32, magic: u32, message in LONG) {
+            round_trip(Packet::ClientQuit(ClientQuit::new(client_id, magic, &message).unwrap()))?;
+        }
+
+        #[test]
+        fn ServerUserLeft(user_id: u32, username in SHORT, message in LONG) {
+            round_trip(Packet::ServerUserLeft(
+                ServerUserLeft::new(user_id, &username, &message).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ServerBroadcastMessage(
+            message_id: u64,
+            timestamp: u64,
+            user_id: u32,
+            room in SHORT,
+            username in SHORT,
+            message in LONG,
+        ) {
+            round_trip(Packet::ServerBroadcastMessage(
+                ServerBroadcastMessage::new(message_id, timestamp, user_id, &room, &username, &message)
+                    .unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ClientJoinRoom(client_id: u32, magic: u32, room in SHORT) {
+            round_trip(Packet::ClientJoinRoom(ClientJoinRoom::new(client_id, magic, &room).unwrap()))?;
+        }
+
+        #[test]
+        fn ClientPartRoom(client_id: u32, magic: u32, room in SHORT) {
+            round_trip(Packet::ClientPartRoom(ClientPartRoom::new(client_id, magic, &room).unwrap()))?;
+        }
+
+        #[test]
+        fn ServerRoomJoined(user_id: u32, username in SHORT, room in SHORT) {
+            round_trip(Packet::ServerRoomJoined(
+                ServerRoomJoined::new(user_id, &username, &room).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ServerRoomParted(user_id: u32, username in SHORT, room in SHORT) {
+            round_trip(Packet::ServerRoomParted(
+                ServerRoomParted::new(user_id, &username, &room).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ClientPrivateMessage(
+            client_id: u32,
+            magic: u32,
+            target_id: u32,
+            target in SHORT,
+            message in LONG,
+        ) {
+            round_trip(Packet::ClientPrivateMessage(
+                ClientPrivateMessage::new(client_id, magic, target_id, &target, &message).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ServerPrivateMessage(user_id: u32, username in SHORT, message in LONG) {
+            round_trip(Packet::ServerPrivateMessage(
+                ServerPrivateMessage::new(user_id, &username, &message).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ClientRosterRequest(client_id: u32, magic: u32) {
+            round_trip(Packet::ClientRosterRequest(ClientRosterRequest { client_id, magic }))?;
+        }
+
+        #[test]
+        fn ServerUserJoined(user_id: u32, username in SHORT) {
+            round_trip(Packet::ServerUserJoined(ServerUserJoined::new(user_id, &username).unwrap()))?;
+        }
+
+        #[test]
+        fn ServerRoster(users in prop::collection::vec((any::<u32>(), SHORT), 0..8)) {
+            let users = users
+                .iter()
+                .map(|(user_id, username)| RosterEntry::new(*user_id, username).unwrap())
+                .collect();
+            round_trip(Packet::ServerRoster(ServerRoster::new(users).unwrap()))?;
+        }
+
+        #[test]
+        fn ClientHistoryRequest(client_id: u32, magic: u32, room in SHORT, before: u64, limit: u16) {
+            round_trip(Packet::ClientHistoryRequest(
+                ClientHistoryRequest::new(client_id, magic, &room, before, limit).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ServerHistory(
+            room in SHORT,
+            messages in prop::collection::vec(
+                (any::<u64>(), any::<u64>(), any::<u32>(), SHORT, SHORT, LONG),
+                0..4,
+            ),
+        ) {
+            let messages = messages
+                .iter()
+                .map(|(message_id, timestamp, user_id, room, username, message)| {
+                    ServerBroadcastMessage::new(*message_id, *timestamp, *user_id, room, username, message)
+                        .unwrap()
+                })
+                .collect();
+            round_trip(Packet::ServerHistory(ServerHistory::new(&room, messages).unwrap()))?;
+        }
+
+        #[test]
+        fn ServerMotd(message in LONG) {
+            round_trip(Packet::ServerMotd(ServerMotd::new(&message).unwrap()))?;
+        }
+
+        #[test]
+        fn ServerAuthChallenge(
+            salt in any::<[u8; crate::auth::SALT_LEN]>(),
+            iterations: u32,
+            nonce in any::<[u8; crate::auth::NONCE_LEN]>(),
+        ) {
+            round_trip(Packet::ServerAuthChallenge(ServerAuthChallenge { salt, iterations, nonce }))?;
+        }
+
+        #[test]
+        fn ClientAuthResponse(proof in any::<[u8; crate::auth::KEY_LEN]>()) {
+            round_trip(Packet::ClientAuthResponse(ClientAuthResponse { proof }))?;
+        }
+
+        #[test]
+        fn ClientChangeNick(client_id: u32, magic: u32, username in SHORT) {
+            round_trip(Packet::ClientChangeNick(
+                ClientChangeNick::new(client_id, magic, &username).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn ServerNickChanged(user_id: u32, old_username in SHORT, username in SHORT) {
+            round_trip(Packet::ServerNickChanged(
+                ServerNickChanged::new(user_id, &old_username, &username).unwrap(),
+            ))?;
+        }
+
+        #[test]
+        fn HeartBeatSend(client_id: u32, magic: u32) {
+            round_trip(Packet::HeartBeatSend(HeartBeatSend { client_id, magic }))?;
+        }
+
+        // Whatever the bytes, the parser returns
+        #[test]
+        fn any_bytes(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            let _ = Packet::from_bytes(&bytes);
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod json {
+    use super::*;
+
+    #[test]
+    fn shape() {
+        let packet = PacketOwned::ServerMotd(ServerMotd::new("hello").unwrap().into_owned());
+        let json = r#"{"type":"ServerMotd","message":"hello"}"#;
+        assert_eq!(serde_json::to_string(&packet).unwrap(), json);
+        assert_eq!(serde_json::from_str::<PacketOwned>(json).unwrap(), packet);
+
+        let packet = Packet::ServerRoster(
+            ServerRoster::new(vec![RosterEntry::new(1, "Maix").unwrap()]).unwrap(),
+        );
+        let json = r#"{"type":"ServerRoster","users":[{"user_id":1,"username":"Maix"}]}"#;
+        assert_eq!(serde_json::to_string(&packet).unwrap(), json);
+        assert_eq!(serde_json::from_str::<Packet>(json).unwrap(), packet);
+    }
+
+    #[test]
+    fn lengths() {
+        // A length sent along is ignored, it can't disagree with its field
+        let json = r#"{"type":"ServerMotd","message_len":9,"message":"hello"}"#;
+        let packet = serde_json::from_str::<PacketOwned>(json).unwrap();
+        assert_eq!(
+            packet,
+            PacketOwned::ServerMotd(ServerMotdOwned {
+                message_len: 5,
+                message: "hello".to_owned(),
+            })
+        );
+
+        let json = format!(
+            r#"{{"type":"ServerMotd","message":"{}"}}"#,
+            "a".repeat(70_000)
+        );
+        let error = serde_json::from_str::<PacketOwned>(&json).unwrap_err();
+        assert!(error.to_string().contains("message"), "{}", error);
+    }
+
+    #[test]
+    fn round_trip() {
+        for raw in super::serialize::PACKETS {
+            let (_, packet) = Packet::from_bytes(raw).unwrap();
+            let json = serde_json::to_string(&packet).unwrap();
+            assert_eq!(serde_json::from_str::<Packet>(&json).unwrap(), packet);
+            let owned = packet.into_owned();
+            assert_eq!(serde_json::to_string(&owned).unwrap(), json);
+            assert_eq!(serde_json::from_str::<PacketOwned>(&json).unwrap(), owned);
+        }
+    }
+}
+
+mod frame {
+    use super::*;
+    use crate::frame::{Frame, FrameDecoder, FrameError, IntoFrame};
+
+    #[test]
+    fn encode() {
+        assert_eq!(HeartBeatRequest {}.unwrap_frame(), b"MC\x00\x00\x00\x03hbr")
+    }
+
+    #[test]
+    fn write_frame() {
+        let packet = ServerMotd::new("hello").unwrap();
+        let frame = packet.unwrap_frame();
+        assert_eq!(packet.frame_len(), frame.len());
+
+        let mut slice = [0u8; 32];
+        assert_eq!(
+            packet.write_frame_to_slice(&mut slice).unwrap(),
+            frame.len()
+        );
+        assert_eq!(&slice[..frame.len()], frame.as_slice());
+        assert!(matches!(
+            packet.write_frame_to_slice(&mut slice[..8]),
+            Err(SerializeError::BufferTooSmall {
+                needed: 16,
+                available: 8
+            })
+        ));
+
+        let mut bytes = bytes::BytesMut::new();
+        assert_eq!(
+            packet.write_frame_to_bytes_mut(&mut bytes).unwrap(),
+            frame.len()
+        );
+        assert_eq!(&bytes[..], frame.as_slice());
+
+        let mut vec = b"MC".to_vec();
+        let bad = ServerMotd {
+            message_len: 4,
+            message: "hello",
+        };
+        assert!(bad.write_frame_to_vec(&mut vec).is_err());
+        assert_eq!(vec, b"MC");
+    }
+
+    #[test]
+    fn decode() {
+        let mut decoder = FrameDecoder::new();
+        let (consumed, frame) = decoder.decode(b"MC\x00\x00\x00\x03hbrMC");
+        assert_eq!(consumed, 9);
+        assert_eq!(
+            frame.unwrap(),
+            Frame::Packet(Packet::HeartBeatRequest(HeartBeatRequest {}))
+        );
+    }
+
+    #[test]
+    fn incomplete() {
+        let mut decoder = FrameDecoder::new();
+        let bytes = HeartBeatSend {
+            client_id: 1,
+            magic: 2,
+        }
+        .unwrap_frame();
+        let (consumed, frame) = decoder.decode(&bytes[..10]);
+        assert_eq!(consumed, 0);
+        assert!(matches!(frame, Err(FrameError::Incomplete(7))));
+        let (consumed, frame) = decoder.decode(&bytes[..4]);
+        assert_eq!(consumed, 0);
+        assert!(matches!(frame, Err(FrameError::Incomplete(2))));
+    }
+
+    #[test]
+    fn resynchronise() {
+        let mut decoder = FrameDecoder::new();
+        let (consumed, frame) = decoder.decode(b"garbageMC\xFF\xFF\xFF\xFFMC\x00\x00\x00\x03hbr");
+        assert_eq!(consumed, 22);
+        assert_eq!(decoder.skipped(), 13);
+        assert_eq!(
+            frame.unwrap(),
+            Frame::Packet(Packet::HeartBeatRequest(HeartBeatRequest {}))
+        );
+
+        let (consumed, frame) = decoder.decode(b"garbageM");
+        assert_eq!(consumed, 7);
+        assert!(matches!(frame, Err(FrameError::Incomplete(5))));
+    }
+
+    #[test]
+    fn unknown() {
+        let mut decoder = FrameDecoder::new();
+        let (consumed, frame) = decoder.decode(b"MC\x00\x00\x00\x05xyz\x01\x02");
+        assert_eq!(consumed, 11);
+        assert_eq!(frame.unwrap(), Frame::Unknown(*b"xyz"));
+    }
+
+    #[test]
+    fn malformed() {
+        let mut decoder = FrameDecoder::new();
+        let (consumed, frame) = decoder.decode(b"MC\x00\x00\x00\x05hbs\x01\x02hbr");
+        assert_eq!(consumed, 11);
+        assert!(matches!(frame, Err(FrameError::Malformed(tag, _)) if &tag == b"hbs"));
+    }
+}
+
+mod decoder {
+    use super::*;
+    use crate::decoder::PacketDecoder;
+    use crate::frame::IntoFrame;
+
+    #[test]
+    fn split() {
+        let mut decoder = PacketDecoder::new();
+        let bytes = ClientSendMessage {
+            client_id: 0x000000FF,
+            magic: 0x0000FF00,
+            room_len: 3,
+            room: "dev",
+            message_len: 6,
+            message: "AZERTY",
+        }
+        .unwrap_frame();
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.feed(&[*byte]);
+            assert_eq!(decoder.next_packet().unwrap(), None);
+        }
+        assert_eq!(decoder.buffered(), bytes.len() - 1);
+        decoder.feed(&bytes[bytes.len() - 1..]);
+        assert_eq!(
+            decoder.next_packet().unwrap(),
+            Some(PacketOwned::ClientSendMessage(ClientSendMessageOwned {
+                client_id: 0x000000FF,
+                magic: 0x0000FF00,
+                room_len: 3,
+                room: String::from("dev"),
+                message_len: 6,
+                message: String::from("AZERTY"),
+            }))
+        );
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn multiple() {
+        let mut decoder = PacketDecoder::new();
+        let mut bytes = HeartBeatRequest {}.unwrap_frame();
+        bytes.extend(b"MC\x00\x00\x00\x04xyz\x00");
+        bytes.extend(
+            HeartBeatSend {
+                client_id: 1,
+                magic: 2,
+            }
+            .unwrap_frame(),
+        );
+        bytes.extend(b"MC\x00\x00");
+        decoder.feed(&bytes);
+        assert_eq!(
+            decoder.next_packet().unwrap(),
+            Some(PacketOwned::HeartBeatRequest(HeartBeatRequest {}))
+        );
+        assert_eq!(
+            decoder.next_packet().unwrap(),
+            Some(PacketOwned::HeartBeatSend(HeartBeatSend {
+                client_id: 1,
+                magic: 2
+            }))
+        );
+        assert_eq!(decoder.next_packet().unwrap(), None);
+        assert_eq!(decoder.unknown(), 1);
+        assert_eq!(decoder.buffered(), 4);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(b"MC\x00\x00\x00\x05crr\x05M");
+        decoder.feed(&HeartBeatRequest {}.unwrap_frame());
+        assert!(decoder.next_packet().is_err());
+        assert_eq!(
+            decoder.next_packet().unwrap(),
+            Some(PacketOwned::HeartBeatRequest(HeartBeatRequest {}))
+        );
+    }
+}
+
+mod protocol {
+    use crate::protocol::*;
+
+    #[test]
+    fn capabilities() {
+        let mut caps = Capabilities(0b101);
+        assert!(caps.contains(Capabilities(0b100)));
+        assert!(!caps.contains(Capabilities(0b110)));
+        caps.insert(Capabilities(0b010));
+        assert_eq!(caps, Capabilities(0b111));
+        caps.remove(Capabilities(0b001));
+        assert_eq!(caps & Capabilities(0b011), Capabilities(0b010));
+        assert_eq!(caps | Capabilities(0b001), Capabilities(0b111));
+    }
+
+    #[test]
+    fn negotiate_downgrade() {
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION + 1, Capabilities(u32::MAX)),
+            Ok((PROTOCOL_VERSION, Capabilities::SUPPORTED))
+        );
+        assert_eq!(
+            negotiate(MIN_PROTOCOL_VERSION, Capabilities::NONE),
+            Ok((MIN_PROTOCOL_VERSION, Capabilities::NONE))
+        );
+    }
+
+    #[test]
+    fn reason_code() {
+        assert_eq!(
+            ReasonCode::from_code(ReasonCode::WrongMagic.code()),
+            ReasonCode::WrongMagic
+        );
+        assert_eq!(ReasonCode::from_code(u16::MAX), ReasonCode::Unknown);
+    }
+
+    #[test]
+    fn usernames() {
+        assert_eq!(username_key("MaiX"), username_key("maix"));
+        assert!(is_reserved_username("System"));
+        assert!(is_reserved_username("sERVER"));
+        assert!(!is_reserved_username("Servers"));
+    }
+
+    #[test]
+    fn negotiate_refuse() {
+        assert_eq!(
+            negotiate(MIN_PROTOCOL_VERSION - 1, Capabilities::SUPPORTED),
+            Err(NegotiationError::TooOld(MIN_PROTOCOL_VERSION - 1))
+        );
+    }
+}
+
+mod auth {
+    use crate::auth::*;
+
+    const SALT: [u8; SALT_LEN] = [0x11; SALT_LEN];
+    const NONCE: [u8; NONCE_LEN] = [0x22; NONCE_LEN];
+
+    #[test]
+    fn proof() {
+        let key = stored_key("hunter2", &SALT, 1000).unwrap();
+        let proof = client_proof("hunter2", &SALT, 1000, "Maix", &NONCE).unwrap();
+        assert!(verify_proof(&key, "Maix", &NONCE, &proof));
+
+        let wrong = client_proof("hunter3", &SALT, 1000, "Maix", &NONCE).unwrap();
+        assert!(!verify_proof(&key, "Maix", &NONCE, &wrong));
+        // A recorded proof is useless for another challenge or user
+        assert!(!verify_proof(&key, "Maix", &[0x23; NONCE_LEN], &proof));
+        assert!(!verify_proof(&key, "Other", &NONCE, &proof));
+        // The stored key alone can't answer
+        assert!(!verify_proof(&key, "Maix", &NONCE, &key));
+
+        assert_eq!(stored_key("hunter2", &SALT, 0), None);
+        assert_eq!(client_proof("hunter2", &SALT, 0, "Maix", &NONCE), None);
+    }
+}
+
+mod validation {
+    use crate::validation::*;
+
+    #[test]
+    fn username() {
+        assert_eq!(
+            crate::validation::username("  Maix "),
+            Ok("Maix".to_string())
+        );
+        // Composed and decomposed forms are the same username
+        assert_eq!(
+            crate::validation::username("Mai\u{0301}x"),
+            Ok("Maíx".to_string())
+        );
+        assert_eq!(
+            crate::validation::username("maix_2.0-b"),
+            Ok("maix_2.0-b".to_string())
+        );
+        assert_eq!(
+            crate::validation::username(" "),
+            Err(ValidationError::Empty)
+        );
+        assert_eq!(
+            crate::validation::username("Ma ix"),
+            Err(ValidationError::ForbiddenChar(' '))
+        );
+        assert_eq!(
+            crate::validation::username("\u{1b}[31mMaix"),
+            Err(ValidationError::ForbiddenChar('\u{1b}'))
+        );
+        assert_eq!(
+            crate::validation::username("Maix\u{202E}"),
+            Err(ValidationError::ForbiddenChar('\u{202E}'))
+        );
+        assert_eq!(
+            crate::validation::username("@Maix"),
+            Err(ValidationError::ForbiddenChar('@'))
+        );
+        // 30 characters but 60 bytes is fine
+        assert!(crate::validation::username(&"é".repeat(MAX_USERNAME_CHARS)).is_ok());
+        assert_eq!(
+            crate::validation::username(&"é".repeat(MAX_USERNAME_CHARS + 1)),
+            Err(ValidationError::TooManyChars {
+                len: MAX_USERNAME_CHARS + 1,
+                max: MAX_USERNAME_CHARS
+            })
+        );
+    }
+
+    #[test]
+    fn message() {
+        assert_eq!(
+            crate::validation::message("\u{1b}[2Jhello\tworld\r\n"),
+            Ok("[2Jhello world".to_string())
+        );
+        assert_eq!(
+            crate::validation::message("\u{7}\u{200B}"),
+            Err(ValidationError::Empty)
+        );
+        assert_eq!(
+            crate::validation::message(&"a".repeat(MAX_MESSAGE_CHARS + 1)),
+            Err(ValidationError::TooManyChars {
+                len: MAX_MESSAGE_CHARS + 1,
+                max: MAX_MESSAGE_CHARS
+            })
+        );
+        assert_eq!(
+            check_length("ééé", 3, 5),
+            Err(ValidationError::TooManyBytes { len: 6, max: 5 })
+        );
+        assert_eq!(strip_control("a\u{0}b\u{202A}c"), "abc");
+        assert_eq!(normalize("e\u{0301}"), "é");
     }
 }
@@ -0,0 +1,115 @@
+//! Checks of the text typed by the users, done by the client before sending and by the
+//! server before broadcasting.
+//!
+//! Text is put in Unicode normalisation form C so the same name always has the same
+//! bytes, and control characters are never let through: an escape sequence in a username
+//! or a message would be interpreted by the terminal of every other user.
+use unicode_normalization::UnicodeNormalization;
+
+/// Maximum length of a username in characters
+pub const MAX_USERNAME_CHARS: usize = 30;
+/// Maximum length of a username in bytes, its length is sent in a u8
+pub const MAX_USERNAME_BYTES: usize = u8::MAX as usize;
+/// Maximum length of a message in characters
+pub const MAX_MESSAGE_CHARS: usize = 2000;
+/// Maximum length of a message in bytes, its length is sent in a u16
+pub const MAX_MESSAGE_BYTES: usize = u16::MAX as usize;
+
+// ASCII punctuation allowed in a username, the rest is kept for commands and mentions
+const USERNAME_PUNCTUATION: &str = "_-.";
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum ValidationError {
+    #[error("can't be empty")]
+    Empty,
+    #[error("{len} characters long, at most {max} allowed")]
+    TooManyChars { len: usize, max: usize },
+    #[error("{len} bytes long, at most {max} allowed")]
+    TooManyBytes { len: usize, max: usize },
+    #[error("{0:?} isn't allowed")]
+    ForbiddenChar(char),
+}
+
+/// Whether the character changes how the text is shown instead of being shown: control
+/// characters, and the invisible formatting ones like the bidirectional overrides
+pub fn is_control(c: char) -> bool {
+    c.is_control()
+        || matches!(
+            c,
+            '\u{AD}'
+                | '\u{200B}'..='\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{2069}'
+                | '\u{FEFF}'
+        )
+}
+
+/// Put the text in normalisation form C
+pub fn normalize(input: &str) -> String {
+    input.nfc().collect()
+}
+
+/// Remove the control characters, tabs and line breaks become spaces
+pub fn strip_control(input: &str) -> String {
+    input
+        .chars()
+        .filter_map(|c| match c {
+            '\t' | '\n' | '\r' => Some(' '),
+            c if is_control(c) => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+/// Check the length of the text, both in characters and in bytes
+pub fn check_length(
+    input: &str,
+    max_chars: usize,
+    max_bytes: usize,
+) -> Result<(), ValidationError> {
+    let chars = input.chars().count();
+    if chars > max_chars {
+        return Err(ValidationError::TooManyChars {
+            len: chars,
+            max: max_chars,
+        });
+    }
+    if input.len() > max_bytes {
+        return Err(ValidationError::TooManyBytes {
+            len: input.len(),
+            max: max_bytes,
+        });
+    }
+    Ok(())
+}
+
+/// The username to use for `input`, normalised and without the surrounding spaces.
+///
+/// Forbidden characters are refused rather than removed, a username is chosen on purpose:
+/// whitespace, control characters and ASCII punctuation other than `_`, `-` and `.`.
+pub fn username(input: &str) -> Result<String, ValidationError> {
+    let username = normalize(input.trim());
+    if username.is_empty() {
+        return Err(ValidationError::Empty);
+    }
+    if let Some(c) = username.chars().find(|&c| {
+        c.is_whitespace()
+            || is_control(c)
+            || (c.is_ascii_punctuation() && !USERNAME_PUNCTUATION.contains(c))
+    }) {
+        return Err(ValidationError::ForbiddenChar(c));
+    }
+    check_length(&username, MAX_USERNAME_CHARS, MAX_USERNAME_BYTES)?;
+    Ok(username)
+}
+
+/// The message to send for `input`, normalised and with its control characters removed
+pub fn message(input: &str) -> Result<String, ValidationError> {
+    let message = normalize(strip_control(input).trim());
+    if message.is_empty() {
+        return Err(ValidationError::Empty);
+    }
+    check_length(&message, MAX_MESSAGE_CHARS, MAX_MESSAGE_BYTES)?;
+    Ok(message)
+}
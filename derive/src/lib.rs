@@ -0,0 +1,72 @@
+//! Derive macros writing the boilerplate of the packets of `maix-chat-common`.
+//!
+//! `#[derive(Packet)]` on a packet struct gives it its wire format, fields are sent in order:
+//!  - `u8`, `u16`, `u32` and `u64` are sent big-endian, `[u8; N]` as is
+//!  - `&'a str` and `Vec<T>` are sent after their length, the `<name>_len` field that must
+//!    come before them, the items of a list are sent one after the other
+//!
+//! It implements `FromBytes` and `IntoBytes`, the `IDENTIFIER` of the `#[packet(tag = "...")]`
+//! attribute, a `new` constructor filling the `*_len` fields when there are some, and for a
+//! struct borrowing its payload the `<Name>Owned` twin with `into_owned` and `as_borrowed`.
+//! A struct without a tag is sent without one, it is a part of another packet.
+//! A parser error tells the tag of the packet, the field that couldn't be read and its offset.
+//!
+//! `#[derive(Packets)]` on the enum of every packet gives it the `<Name>Owned` twin, the
+//! dispatch of `FromBytes` and `IntoBytes` on the tag, `into_owned` and `get_identifier`.
+//!
+//! With the `serde` feature, packets and their owned twins implement `Serialize` and
+//! `Deserialize` without their `*_len` fields, a length is derived from its field when read.
+//!
+//! The generated code uses the `crate::` paths of `maix-chat-common`, it can only be used there.
+extern crate proc_macro;
+
+mod packet;
+mod packets;
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, DeriveInput};
+
+#[proc_macro_derive(Packet, attributes(packet))]
+pub fn derive_packet(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    packet::expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(Packets)]
+pub fn derive_packets(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    packets::expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+// Whether the type borrows, it has a lifetime parameter
+fn borrows(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Reference(_) => true,
+        syn::Type::Path(path) => path.path.segments.iter().any(|segment| {
+            matches!(&segment.arguments, syn::PathArguments::AngleBracketed(args)
+            if args.args.iter().any(|arg| match arg {
+                syn::GenericArgument::Lifetime(_) => true,
+                syn::GenericArgument::Type(ty) => borrows(ty),
+                _ => false,
+            }))
+        }),
+        _ => false,
+    }
+}
+
+// The name of the type without its generic parameters, `RosterEntry` for `RosterEntry<'a>`
+fn bare_name(ty: &syn::Type) -> syn::Result<&syn::Ident> {
+    match ty {
+        syn::Type::Path(path) if path.qself.is_none() => path
+            .path
+            .segments
+            .last()
+            .map(|segment| &segment.ident)
+            .ok_or_else(|| syn::Error::new_spanned(ty, "expected a type name")),
+        _ => Err(syn::Error::new_spanned(ty, "expected a type name")),
+    }
+}
@@ -0,0 +1,453 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{Data, DeriveInput, Fields, Ident, LitByteStr, LitStr, Type};
+
+use crate::{bare_name, borrows};
+
+// How a field is sent
+enum Kind<'a> {
+    // a number or an array, sent as is
+    Plain,
+    // a string sent after its length
+    Str,
+    // a list sent after its length, of this item type
+    List(&'a Type),
+}
+
+struct Field<'a> {
+    ident: &'a Ident,
+    ty: &'a Type,
+    kind: Kind<'a>,
+    // the field this one is the length of
+    len_of: Option<&'a Ident>,
+    docs: Vec<&'a syn::Attribute>,
+}
+
+pub(crate) fn expand(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let tag = tag(input)?;
+    let fields = fields(input)?;
+
+    if input.generics.type_params().next().is_some()
+        || input.generics.const_params().next().is_some()
+        || input.generics.lifetimes().count() > 1
+    {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "a packet can only have a lifetime parameter",
+        ));
+    }
+    let lifetime = input.generics.lifetimes().next().map(|l| &l.lifetime);
+    let (impl_generics, ty_generics, _) = input.generics.split_for_impl();
+    // the lifetime of the parsed bytes
+    let input_lifetime = match lifetime {
+        Some(lifetime) => quote!(#lifetime),
+        None => quote!('a),
+    };
+
+    let idents = fields.iter().map(|f| f.ident).collect::<Vec<_>>();
+
+    let identifier = tag.as_ref().map(|tag| {
+        quote! {
+            const IDENTIFIER: [u8; 3] = *#tag;
+            pub fn get_identifier(&self) -> [u8; 3] {
+                Self::IDENTIFIER
+            }
+        }
+    });
+    let tag_len = if tag.is_some() { 3usize } else { 0 };
+    let parse_tag = tag
+        .as_ref()
+        .map(|_| quote!(let (input, _) = crate::parser::tag(&Self::IDENTIFIER, input)?;));
+    let write_tag = tag.as_ref().map(
+        |_| quote!(let context = ::cookie_factory::combinator::slice(&Self::IDENTIFIER)(context)?;),
+    );
+
+    let min_lens = fields.iter().filter_map(|f| match f.kind {
+        Kind::Plain => {
+            let ty = f.ty;
+            Some(quote!(<#ty as crate::parser::ReadField<#input_lifetime>>::LEN))
+        }
+        Kind::Str | Kind::List(_) => None,
+    });
+    // errors tell the tag of the packet and the field read
+    let error_tag = match tag {
+        Some(_) => quote!(Some(Self::IDENTIFIER)),
+        None => quote!(None),
+    };
+    let reads = fields.iter().map(|f| {
+        let ident = f.ident;
+        let read = match &f.kind {
+            Kind::Plain => quote!(crate::parser::ReadField::read(input)),
+            Kind::Str | Kind::List(_) => {
+                let len = format_ident!("{}_len", ident);
+                let read = match f.kind {
+                    Kind::Str => quote!(crate::parser::string),
+                    _ => quote!(crate::parser::list),
+                };
+                quote!(#read(input, #len as usize))
+            }
+        };
+        let name = ident.to_string();
+        quote! {
+            let (input, #ident) = #read.map_err(|e| {
+                crate::parser::within(e, #error_tag, Some(#name), start, input)
+            })?;
+        }
+    });
+    let writes = fields.iter().map(|f| {
+        let ident = f.ident;
+        match f.len_of {
+            Some(of) => quote! {
+                let context = crate::serializer::WriteField::write_field(
+                    &crate::serializer::checked_len(self.#ident, self.#of.len())?,
+                    context,
+                )?;
+            },
+            None => quote! {
+                let context = crate::serializer::WriteField::write_field(&self.#ident, context)?;
+            },
+        }
+    });
+
+    let new = constructor(&fields);
+    let types = fields
+        .iter()
+        .map(|f| f.ty)
+        .map(|ty| quote!(#ty))
+        .collect::<Vec<_>>();
+    let serde = serde(name, lifetime, &fields, &types);
+    let owned = match lifetime {
+        Some(_) => Some(owned(name, vis, &tag, &fields)?),
+        None => None,
+    };
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics {
+            #identifier
+            #new
+        }
+
+        impl<#input_lifetime> crate::parser::FromBytes<#input_lifetime> for #name #ty_generics {
+            const MIN_LEN: usize = #tag_len #(+ #min_lens)*;
+
+            fn from_bytes(
+                input: &#input_lifetime [u8],
+            ) -> crate::parser::nom::IResult<&#input_lifetime [u8], Self, crate::parser::ParserError>
+            {
+                let start = input;
+                #parse_tag
+                #(#reads)*
+                Ok((input, Self { #(#idents),* }))
+            }
+        }
+
+        impl #impl_generics crate::serializer::IntoBytes for #name #ty_generics {
+            fn encoded_len(&self) -> usize {
+                #tag_len #(+ crate::serializer::WriteField::field_len(&self.#idents))*
+            }
+
+            fn serialize<W: ::std::io::Write>(
+                &self,
+                context: ::cookie_factory::WriteContext<W>,
+            ) -> ::cookie_factory::GenResult<W> {
+                #write_tag
+                #(#writes)*
+                Ok(context)
+            }
+        }
+
+        #serde
+
+        #owned
+    })
+}
+
+// `Serialize` and `Deserialize` with the `serde` feature, of every field but the `*_len` ones,
+// the lengths are derived back from their field on deserialize
+fn serde(
+    name: &Ident,
+    lifetime: Option<&syn::Lifetime>,
+    fields: &[Field<'_>],
+    types: &[TokenStream],
+) -> TokenStream {
+    let sent = fields
+        .iter()
+        .zip(types)
+        .filter(|(f, _)| f.len_of.is_none())
+        .collect::<Vec<_>>();
+    let count = sent.len();
+    let idents = sent.iter().map(|(f, _)| f.ident).collect::<Vec<_>>();
+    let names = idents.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+    let declarations = sent.iter().map(|(f, ty)| {
+        let ident = f.ident;
+        // strings are borrowed by serde on its own, the items of a list must be told to
+        let borrow = match f.kind {
+            Kind::List(item) if lifetime.is_some() && borrows(item) => {
+                Some(quote!(#[serde(borrow)]))
+            }
+            _ => None,
+        };
+        quote!(#borrow #ident: #ty)
+    });
+    let inits = fields.iter().map(|f| {
+        let ident = f.ident;
+        match f.len_of {
+            Some(of) => {
+                let (ty, name) = (f.ty, of.to_string());
+                quote! {
+                    #ident: crate::length::<#ty>(#name, fields.#of.len())
+                        .map_err(<D::Error as ::serde::de::Error>::custom)?
+                }
+            }
+            None => quote!(#ident: fields.#ident),
+        }
+    });
+    let name_str = name.to_string();
+    let (generics, de_generics) = match lifetime {
+        Some(lifetime) => (quote!(<#lifetime>), quote!(<'de: #lifetime, #lifetime>)),
+        None => (quote!(), quote!(<'de>)),
+    };
+
+    quote! {
+        #[cfg(feature = "serde")]
+        impl #generics ::serde::Serialize for #name #generics {
+            fn serialize<S: ::serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> ::std::result::Result<S::Ok, S::Error> {
+                use ::serde::ser::SerializeStruct;
+                let mut state = serializer.serialize_struct(#name_str, #count)?;
+                #(state.serialize_field(#names, &self.#idents)?;)*
+                state.end()
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl #de_generics ::serde::Deserialize<'de> for #name #generics {
+            fn deserialize<D: ::serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> ::std::result::Result<Self, D::Error> {
+                #[derive(::serde::Deserialize)]
+                #[serde(rename = #name_str)]
+                struct Fields #generics {
+                    #(#declarations,)*
+                }
+
+                let fields = Fields::deserialize(deserializer)?;
+                Ok(Self { #(#inits),* })
+            }
+        }
+    }
+}
+
+// The tag of the `#[packet(tag = "...")]` attribute
+fn tag(input: &DeriveInput) -> syn::Result<Option<LitByteStr>> {
+    let mut tag = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                let value: LitStr = meta.value()?.parse()?;
+                if value.value().len() != 3 || !value.value().is_ascii() {
+                    return Err(meta.error("a tag is 3 ASCII characters"));
+                }
+                tag = Some(LitByteStr::new(value.value().as_bytes(), value.span()));
+                Ok(())
+            } else {
+                Err(meta.error("unknown packet attribute, expected `tag`"))
+            }
+        })?;
+    }
+    Ok(tag)
+}
+
+fn fields(input: &DeriveInput) -> syn::Result<Vec<Field<'_>>> {
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().collect::<Vec<_>>(),
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(_) => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "the fields of a packet must be named",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "a packet must be a struct",
+            ))
+        }
+    };
+    let mut fields = named
+        .iter()
+        .map(|f| Field {
+            ident: f.ident.as_ref().unwrap(),
+            ty: &f.ty,
+            kind: kind(&f.ty),
+            len_of: None,
+            docs: f
+                .attrs
+                .iter()
+                .filter(|a| a.path().is_ident("doc"))
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+
+    // Every string and list needs its length before it
+    for i in 0..fields.len() {
+        if let Kind::Plain = fields[i].kind {
+            continue;
+        }
+        let ident = fields[i].ident;
+        let len = format_ident!("{}_len", ident);
+        match fields[..i].iter().position(|f| *f.ident == len) {
+            Some(position) => fields[position].len_of = Some(ident),
+            None => {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    format!("`{}` needs a `{}` field before it", ident, len),
+                ))
+            }
+        }
+    }
+    Ok(fields)
+}
+
+fn kind(ty: &Type) -> Kind<'_> {
+    match ty {
+        Type::Reference(reference) => match &*reference.elem {
+            Type::Path(path) if path.path.is_ident("str") => Kind::Str,
+            _ => Kind::Plain,
+        },
+        Type::Path(path) => {
+            let last = path.path.segments.last().unwrap();
+            match &last.arguments {
+                syn::PathArguments::AngleBracketed(args) if last.ident == "Vec" => {
+                    match args.args.first() {
+                        Some(syn::GenericArgument::Type(item)) => Kind::List(item),
+                        _ => Kind::Plain,
+                    }
+                }
+                _ => Kind::Plain,
+            }
+        }
+        _ => Kind::Plain,
+    }
+}
+
+// `new`, taking the payload and filling the `*_len` fields
+fn constructor(fields: &[Field<'_>]) -> Option<TokenStream> {
+    let lens = fields
+        .iter()
+        .filter(|f| f.len_of.is_some())
+        .map(|f| format!("`{}`", f.ident))
+        .collect::<Vec<_>>();
+    if lens.is_empty() {
+        return None;
+    }
+    let doc = format!(
+        "Build the packet with {} derived from the payload",
+        lens.join(" and ")
+    );
+    let params = fields.iter().filter(|f| f.len_of.is_none()).map(|f| {
+        let (ident, ty) = (f.ident, f.ty);
+        quote!(#ident: #ty)
+    });
+    let inits = fields.iter().map(|f| {
+        let ident = f.ident;
+        match f.len_of {
+            Some(of) => {
+                let (ty, name) = (f.ty, of.to_string());
+                quote!(#ident: crate::length::<#ty>(#name, #of.len())?)
+            }
+            None => quote!(#ident),
+        }
+    });
+    Some(quote! {
+        #[doc = #doc]
+        pub fn new(#(#params),*) -> ::std::result::Result<Self, crate::LengthError> {
+            Ok(Self { #(#inits),* })
+        }
+    })
+}
+
+// The `<Name>Owned` twin of a packet borrowing its payload
+fn owned(
+    name: &Ident,
+    vis: &syn::Visibility,
+    tag: &Option<LitByteStr>,
+    fields: &[Field<'_>],
+) -> syn::Result<TokenStream> {
+    let owned = format_ident!("{}Owned", name);
+    let doc = format!("Owned version of [`{}`]", name);
+    let idents = fields.iter().map(|f| f.ident).collect::<Vec<_>>();
+    let docs = fields.iter().map(|f| &f.docs);
+
+    let mut types = Vec::new();
+    let mut to_owned = Vec::new();
+    let mut borrow = Vec::new();
+    for f in fields {
+        let (ident, ty) = (f.ident, f.ty);
+        match &f.kind {
+            Kind::Str => {
+                types.push(quote!(::std::string::String));
+                to_owned.push(quote!(self.#ident.to_owned()));
+                borrow.push(quote!(self.#ident.as_str()));
+            }
+            Kind::List(item) if borrows(item) => {
+                let item = bare_name(item)?;
+                let item_owned = format_ident!("{}Owned", item);
+                types.push(quote!(::std::vec::Vec<#item_owned>));
+                to_owned.push(quote!(self.#ident.iter().map(#item::into_owned).collect()));
+                borrow.push(quote!(self.#ident.iter().map(#item_owned::as_borrowed).collect()));
+            }
+            Kind::List(_) => {
+                types.push(quote!(#ty));
+                to_owned.push(quote!(self.#ident.clone()));
+                borrow.push(quote!(self.#ident.clone()));
+            }
+            Kind::Plain => {
+                types.push(quote!(#ty));
+                to_owned.push(quote!(self.#ident));
+                borrow.push(quote!(self.#ident));
+            }
+        }
+    }
+    let identifier = tag.as_ref().map(|tag| {
+        quote! {
+            const IDENTIFIER: [u8; 3] = *#tag;
+            pub fn get_identifier(&self) -> [u8; 3] {
+                Self::IDENTIFIER
+            }
+        }
+    });
+    let serde = serde(&owned, None, fields, &types);
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(Debug, Clone, Eq, PartialEq)]
+        #vis struct #owned {
+            #(#(#docs)* pub #idents: #types,)*
+        }
+
+        impl #name<'_> {
+            pub fn into_owned(&self) -> #owned {
+                #owned { #(#idents: #to_owned),* }
+            }
+        }
+
+        impl #owned {
+            #identifier
+
+            /// Borrow the packet, to serialize it again
+            pub fn as_borrowed(&self) -> #name<'_> {
+                #name { #(#idents: #borrow),* }
+            }
+        }
+
+        #serde
+    })
+}
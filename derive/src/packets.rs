@@ -0,0 +1,152 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{Data, DeriveInput, Fields, Ident, Type};
+
+use crate::{bare_name, borrows};
+
+struct Variant<'a> {
+    ident: &'a Ident,
+    ty: &'a Type,
+    // the name of the packet type, to reach its `IDENTIFIER`
+    packet: &'a Ident,
+    borrows: bool,
+}
+
+pub(crate) fn expand(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let owned = format_ident!("{}Owned", name);
+    let doc = format!("Owned version of [`{}`]", name);
+    let variants = variants(input)?;
+
+    let lifetime = match input.generics.lifetimes().next() {
+        Some(l) if input.generics.params.len() == 1 => &l.lifetime,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.generics,
+                "the packet enum must have a single lifetime parameter",
+            ))
+        }
+    };
+
+    let idents = variants.iter().map(|v| v.ident).collect::<Vec<_>>();
+    let types = variants.iter().map(|v| v.ty).collect::<Vec<_>>();
+    let packets = variants.iter().map(|v| v.packet).collect::<Vec<_>>();
+    let owned_types = variants.iter().map(|v| {
+        if v.borrows {
+            let owned = format_ident!("{}Owned", v.packet);
+            quote!(#owned)
+        } else {
+            let ty = v.ty;
+            quote!(#ty)
+        }
+    });
+    let to_owned = variants.iter().map(|v| {
+        if v.borrows {
+            quote!(inner.into_owned())
+        } else {
+            quote!(*inner)
+        }
+    });
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(Debug, Clone, Eq, PartialEq)]
+        #[cfg_attr(
+            feature = "serde",
+            derive(::serde::Serialize, ::serde::Deserialize),
+            serde(tag = "type")
+        )]
+        #[non_exhaustive]
+        #vis enum #owned {
+            #(#idents(#owned_types),)*
+        }
+
+        impl<#lifetime> #name<#lifetime> {
+            pub fn into_owned(&self) -> #owned {
+                match self {
+                    #(#name::#idents(inner) => #owned::#idents(#to_owned),)*
+                }
+            }
+
+            pub fn get_identifier(&self) -> [u8; 3] {
+                match self {
+                    #(#name::#idents(inner) => inner.get_identifier(),)*
+                }
+            }
+        }
+
+        impl #owned {
+            pub fn get_identifier(&self) -> [u8; 3] {
+                match self {
+                    #(#owned::#idents(inner) => inner.get_identifier(),)*
+                }
+            }
+        }
+
+        impl<#lifetime> crate::parser::FromBytes<#lifetime> for #name<#lifetime> {
+            const MIN_LEN: usize = 3;
+
+            fn from_bytes(
+                input: &#lifetime [u8],
+            ) -> crate::parser::nom::IResult<&#lifetime [u8], Self, crate::parser::ParserError> {
+                match crate::parser::peek_tag(input)? {
+                    #(#packets::IDENTIFIER => {
+                        <#types as crate::parser::FromBytes<#lifetime>>::from_bytes(input)
+                            .map(|(input, packet)| (input, #name::#idents(packet)))
+                    })*
+                    tag => Err(crate::parser::invalid_tag(tag)),
+                }
+            }
+        }
+
+        impl<#lifetime> crate::serializer::IntoBytes for #name<#lifetime> {
+            fn encoded_len(&self) -> usize {
+                match self {
+                    #(#name::#idents(inner) => crate::serializer::IntoBytes::encoded_len(inner),)*
+                }
+            }
+
+            fn serialize<W: ::std::io::Write>(
+                &self,
+                context: ::cookie_factory::WriteContext<W>,
+            ) -> ::cookie_factory::GenResult<W> {
+                match self {
+                    #(#name::#idents(inner) => {
+                        crate::serializer::IntoBytes::serialize(inner, context)
+                    })*
+                }
+            }
+        }
+    })
+}
+
+fn variants(input: &DeriveInput) -> syn::Result<Vec<Variant<'_>>> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "`Packets` is derived on the enum of the packets",
+            ))
+        }
+    };
+    data.variants
+        .iter()
+        .map(|variant| match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                let ty = &fields.unnamed[0].ty;
+                Ok(Variant {
+                    ident: &variant.ident,
+                    ty,
+                    packet: bare_name(ty)?,
+                    borrows: borrows(ty),
+                })
+            }
+            _ => Err(syn::Error::new_spanned(
+                variant,
+                "a variant holds a single packet",
+            )),
+        })
+        .collect()
+}
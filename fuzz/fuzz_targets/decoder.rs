@@ -0,0 +1,22 @@
+//! Feed any bytes to the streaming decoder, in chunks: it must not panic, and it
+//! must not buffer more than one frame waiting for the rest of it.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use maix_chat_common::{decoder::PacketDecoder, frame::FRAME_HEADER_SIZE};
+
+const MAX_PAYLOAD: usize = 1024;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte tells the size of the chunks read from the "socket"
+    let (chunk, data) = match data.split_first() {
+        Some((chunk, data)) => (*chunk as usize + 1, data),
+        None => return,
+    };
+    let mut decoder = PacketDecoder::with_max_payload(MAX_PAYLOAD);
+    for chunk in data.chunks(chunk) {
+        decoder.feed(chunk);
+        // Errors are malformed packets, the decoder goes on after them
+        while !matches!(decoder.next_packet(), Ok(None)) {}
+        assert!(decoder.buffered() < FRAME_HEADER_SIZE + MAX_PAYLOAD);
+    }
+});
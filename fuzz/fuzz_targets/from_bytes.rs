@@ -0,0 +1,13 @@
+//! Parse a packet out of any bytes: it must not panic, and a packet that is read
+//! must serialize back to the bytes it was read from.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use maix_chat_common::{parser::FromBytes, serializer::IntoBytes, Packet};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((rest, packet)) = Packet::from_bytes(data) {
+        let read = &data[..data.len() - rest.len()];
+        assert_eq!(packet.encoded_len(), read.len());
+        assert_eq!(packet.unwrap_bytes(), read);
+    }
+});
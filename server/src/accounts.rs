@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use common::{
+    auth::{self, KEY_LEN, SALT_LEN},
+    protocol::username_key,
+};
+
+/// What is kept of the password of an account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Account {
+    /// Username as it was registered
+    pub(crate) username: String,
+    pub(crate) salt: [u8; SALT_LEN],
+    pub(crate) iterations: u32,
+    pub(crate) stored_key: [u8; KEY_LEN],
+}
+
+/// Registered usernames, saved in a text file.
+///
+/// Every line is `username salt iterations stored_key` with the salt and key in hex,
+/// empty lines and lines starting with `#` are ignored. Usernames are matched regardless of case.
+#[derive(Debug)]
+pub(crate) struct Accounts {
+    path: PathBuf,
+    // by `username_key`
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    /// Load the accounts of `path`, a missing file has no account
+    pub(crate) fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut accounts = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let account = parse_line(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "`{}` line {} isn't a valid account",
+                        path.display(),
+                        number + 1
+                    ),
+                )
+            })?;
+            accounts.insert(username_key(&account.username), account);
+        }
+        Ok(Self { path, accounts })
+    }
+
+    pub(crate) fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(&username_key(username))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Create the account or change its password, `save` must be called to keep it
+    pub(crate) fn set_password(&mut self, username: &str, password: &str) {
+        let mut salt = [0u8; SALT_LEN];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut salt);
+        let iterations = auth::DEFAULT_ITERATIONS;
+        let stored_key =
+            auth::stored_key(password, &salt, iterations).expect("the default iterations aren't 0");
+        self.accounts.insert(
+            username_key(username),
+            Account {
+                username: username.to_string(),
+                salt,
+                iterations,
+                stored_key,
+            },
+        );
+    }
+
+    /// Write every account back to the file
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let mut accounts: Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_by(|a, b| a.username.cmp(&b.username));
+        let mut content = String::from("# username salt iterations stored_key\n");
+        for account in accounts {
+            content.push_str(&format!(
+                "{} {} {} {}\n",
+                account.username,
+                to_hex(&account.salt),
+                account.iterations,
+                to_hex(&account.stored_key)
+            ));
+        }
+        // Replace the file at once so a crash can't leave it half written
+        let tmp = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+fn parse_line(line: &str) -> Option<Account> {
+    let mut fields = line.split_whitespace();
+    let username = fields.next()?;
+    let mut salt = [0u8; SALT_LEN];
+    from_hex(fields.next()?, &mut salt)?;
+    let iterations = fields.next()?.parse().ok().filter(|i| *i > 0)?;
+    let mut stored_key = [0u8; KEY_LEN];
+    from_hex(fields.next()?, &mut stored_key)?;
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(Account {
+        username: username.to_string(),
+        salt,
+        iterations,
+        stored_key,
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str, out: &mut [u8]) -> Option<()> {
+    if hex.len() != out.len() * 2 || !hex.is_ascii() {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
@@ -0,0 +1,56 @@
+use std::ops::Range;
+
+use common::{frame::IntoFrame, protocol::Capabilities};
+
+/// Who a message of the broadcast queue is for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Audience {
+    Everyone,
+    Room(String),
+    // the clients that negotiated the capability
+    With(Capabilities),
+    // the clients that didn't negotiate the capability
+    Without(Capabilities),
+}
+
+/// Frames to send once every packet of the loop is processed.
+///
+/// Each frame is encoded once in a buffer kept from loop to loop, broadcasting doesn't
+/// allocate once the buffer has grown to the usual traffic.
+#[derive(Debug, Default)]
+pub(crate) struct Broadcasts {
+    buffer: Vec<u8>,
+    frames: Vec<(Audience, Range<usize>)>,
+}
+
+impl Broadcasts {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue the packet for the audience, a packet that can't be encoded is logged and dropped
+    pub(crate) fn push(&mut self, audience: Audience, packet: &impl IntoFrame) {
+        let start = self.buffer.len();
+        match packet.write_frame_to_vec(&mut self.buffer) {
+            Ok(len) => self.frames.push((audience, start..start + len)),
+            Err(e) => error!("Unable to encode a packet to broadcast: {}", e),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// The queued frames, in the order they were pushed
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Audience, &[u8])> {
+        self.frames
+            .iter()
+            .map(move |(audience, range)| (audience, &self.buffer[range.clone()]))
+    }
+
+    /// Forget the queued frames, the memory is kept for the next ones
+    pub(crate) fn clear(&mut self) {
+        self.buffer.clear();
+        self.frames.clear();
+    }
+}
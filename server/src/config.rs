@@ -0,0 +1,472 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use common::{
+    frame::{DEFAULT_MAX_PAYLOAD, FRAME_HEADER_SIZE},
+    validation::{MAX_MESSAGE_CHARS, MAX_USERNAME_BYTES, MAX_USERNAME_CHARS},
+};
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::{listener::ListenAddr, rooms::MAX_ROOM_LEN};
+
+/// Command line options, they take precedence over the configuration file
+#[derive(Debug, Parser)]
+#[command(name = "maix-chat-server", version, about = "Maix chat server")]
+pub(crate) struct Args {
+    /// TOML configuration file
+    #[arg(short, long, value_name = "FILE")]
+    pub(crate) config: Option<PathBuf>,
+    /// Address to listen on, `unix:PATH` for a Unix socket, can be given several times
+    #[arg(short, long, value_name = "ADDR")]
+    pub(crate) listen: Vec<ListenAddr>,
+    /// Permissions of the Unix sockets, in octal
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub(crate) unix_socket_mode: Option<u32>,
+    /// PEM certificate chain of the `tls:` listeners
+    #[arg(long, value_name = "FILE")]
+    pub(crate) tls_cert: Option<PathBuf>,
+    /// PEM private key of the `tls:` listeners
+    #[arg(long, value_name = "FILE")]
+    pub(crate) tls_key: Option<PathBuf>,
+    /// Message of the day sent to every client once registered
+    #[arg(long, value_name = "TEXT")]
+    pub(crate) motd: Option<String>,
+    /// Maximum number of connected clients
+    #[arg(long, value_name = "COUNT")]
+    pub(crate) max_clients: Option<usize>,
+    /// Bytes waiting to be sent to a client before it is disconnected
+    #[arg(long, value_name = "BYTES", env = "MAIX_CHAT_HIGH_WATER")]
+    pub(crate) high_water: Option<usize>,
+    /// Time without news from a client before asking for a heartbeat, in milliseconds
+    #[arg(long, value_name = "MS")]
+    pub(crate) heartbeat_interval: Option<u64>,
+    /// Unanswered heartbeat requests before a client is disconnected
+    #[arg(long, value_name = "COUNT")]
+    pub(crate) heartbeat_max_skipped: Option<u8>,
+    /// Time after which the unanswered heartbeat requests are forgotten, in milliseconds
+    #[arg(long, value_name = "MS")]
+    pub(crate) heartbeat_reset: Option<u64>,
+    /// File of the registered usernames and their passwords
+    #[arg(long, value_name = "FILE", env = "MAIX_CHAT_ACCOUNTS")]
+    pub(crate) accounts: Option<PathBuf>,
+    /// Only let the registered usernames in
+    #[arg(long)]
+    pub(crate) no_guests: bool,
+    /// Create an account or change its password, read on the standard input, and exit
+    #[arg(long, value_name = "USERNAME", requires = "accounts")]
+    pub(crate) add_account: Option<String>,
+    /// Append-only log of the messages, they are only kept in memory without it
+    #[arg(long, value_name = "FILE", env = "MAIX_CHAT_HISTORY")]
+    pub(crate) history_file: Option<PathBuf>,
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    pub(crate) log_level: Option<LevelFilter>,
+    /// Write the logs to this file instead of the terminal
+    #[arg(long, value_name = "FILE")]
+    pub(crate) log_file: Option<PathBuf>,
+    /// Check the configuration and exit
+    #[arg(long)]
+    pub(crate) check: bool,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum ConfigError {
+    #[error("unable to read `{}`: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid configuration file `{}`: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: Box<toml::de::Error>,
+    },
+    #[error("invalid `{key}`: {reason}")]
+    Invalid { key: &'static str, reason: String },
+}
+
+/// Settings of the server, every key of the file is optional.
+///
+/// ```toml
+/// listen = ["0.0.0.0:8888", "[::]:8888", "unix:/run/maix-chat.sock"]
+/// unix_socket_mode = 0o660
+/// motd = "Welcome!"
+///
+/// [tls]
+/// cert = "cert.pem"
+/// key = "key.pem"
+///
+/// [accounts]
+/// file = "accounts.txt"
+/// allow_guests = true
+///
+/// [limits]
+/// max_clients = 1000
+/// outbound_high_water = 1048576
+///
+/// [heartbeat]
+/// interval_ms = 2000
+/// max_skipped = 5
+/// reset_ms = 30000
+///
+/// [history]
+/// file = "history.log"
+/// capacity = 1000
+/// replay = 50
+/// page_limit = 100
+///
+/// [log]
+/// level = "info"
+/// file = "server.log"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) listen: Vec<ListenAddr>,
+    /// Permissions of the Unix sockets
+    pub(crate) unix_socket_mode: u32,
+    pub(crate) motd: Option<String>,
+    pub(crate) tls: Tls,
+    pub(crate) accounts: Accounts,
+    pub(crate) limits: Limits,
+    pub(crate) heartbeat: Heartbeat,
+    pub(crate) history: History,
+    pub(crate) log: Log,
+}
+
+/// Certificate of the `tls:` listeners
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Tls {
+    /// PEM certificate chain, the server's own first
+    pub(crate) cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    pub(crate) key: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Accounts {
+    /// Registered usernames, see `accounts::Accounts`
+    pub(crate) file: Option<PathBuf>,
+    /// Let in the usernames without an account, without a password
+    pub(crate) allow_guests: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Limits {
+    pub(crate) max_clients: usize,
+    /// Bytes that can wait to be sent to a client before it is disconnected
+    pub(crate) outbound_high_water: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Heartbeat {
+    pub(crate) interval_ms: u64,
+    pub(crate) max_skipped: u8,
+    pub(crate) reset_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct History {
+    /// Path of the log, kept in memory only if unset
+    pub(crate) file: Option<PathBuf>,
+    /// Messages kept in memory for each room
+    pub(crate) capacity: usize,
+    /// Messages sent to a client joining a room
+    pub(crate) replay: usize,
+    /// Maximum number of messages sent for a history request
+    pub(crate) page_limit: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Log {
+    pub(crate) level: LevelFilter,
+    /// Logs go to the terminal if unset
+    pub(crate) file: Option<PathBuf>,
+}
+
+// the biggest `ServerBroadcastMessage` of a checked message, a character takes up to 4 bytes
+const MAX_MESSAGE_PACKET: usize =
+    3 + 8 + 8 + 4 + 1 + MAX_ROOM_LEN + 1 + MAX_USERNAME_CHARS * 4 + 2 + MAX_MESSAGE_CHARS * 4;
+// a `ServerHistory` without its messages
+const PAGE_OVERHEAD: usize = 3 + 1 + MAX_ROOM_LEN + 2;
+/// The most addresses the server listens on, their tokens come before the ones of the clients
+pub(crate) const MAX_LISTEN: usize = 255;
+// a single message must always fit in the outbound queue
+const MIN_HIGH_WATER: usize = 1 << 16;
+const _: () = assert!(MIN_HIGH_WATER >= FRAME_HEADER_SIZE + MAX_MESSAGE_PACKET);
+// a `RosterEntry` of the longest username
+const MAX_ROSTER_ENTRY: usize = 4 + 1 + MAX_USERNAME_BYTES;
+// a `ServerRoster` without its entries
+const ROSTER_OVERHEAD: usize = 3 + 2;
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![ListenAddr::Tcp(([127, 0, 0, 1], 8888).into())],
+            unix_socket_mode: 0o660,
+            motd: None,
+            tls: Tls::default(),
+            accounts: Accounts::default(),
+            limits: Limits::default(),
+            heartbeat: Heartbeat::default(),
+            history: History::default(),
+            log: Log::default(),
+        }
+    }
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Self {
+            file: None,
+            allow_guests: true,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_clients: 1024,
+            outbound_high_water: 1 << 20,
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval_ms: 2000,
+            max_skipped: 5,
+            reset_ms: 30_000,
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            file: None,
+            capacity: 1000,
+            replay: 50,
+            page_limit: 100,
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Trace,
+            file: None,
+        }
+    }
+}
+
+impl Heartbeat {
+    /// Time without news from a client before asking for a heartbeat
+    pub(crate) fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    /// Time after which the unanswered heartbeat requests are forgotten
+    pub(crate) fn reset(&self) -> Duration {
+        Duration::from_millis(self.reset_ms)
+    }
+}
+
+impl Config {
+    /// The configuration given by the command line and the file it points to
+    pub(crate) fn load(args: &Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Self::read(path)?,
+            None => Self::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read a configuration file, without validating it
+    pub(crate) fn read(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&content).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source: Box::new(source),
+        })
+    }
+
+    /// Override the settings given on the command line
+    pub(crate) fn apply(&mut self, args: &Args) {
+        if !args.listen.is_empty() {
+            self.listen = args.listen.clone();
+        }
+        if let Some(mode) = args.unix_socket_mode {
+            self.unix_socket_mode = mode;
+        }
+        if let Some(motd) = &args.motd {
+            self.motd = Some(motd.clone());
+        }
+        if let Some(cert) = &args.tls_cert {
+            self.tls.cert = Some(cert.clone());
+        }
+        if let Some(key) = &args.tls_key {
+            self.tls.key = Some(key.clone());
+        }
+        if let Some(file) = &args.accounts {
+            self.accounts.file = Some(file.clone());
+        }
+        if args.no_guests {
+            self.accounts.allow_guests = false;
+        }
+        if let Some(max_clients) = args.max_clients {
+            self.limits.max_clients = max_clients;
+        }
+        if let Some(high_water) = args.high_water {
+            self.limits.outbound_high_water = high_water;
+        }
+        if let Some(interval) = args.heartbeat_interval {
+            self.heartbeat.interval_ms = interval;
+        }
+        if let Some(max_skipped) = args.heartbeat_max_skipped {
+            self.heartbeat.max_skipped = max_skipped;
+        }
+        if let Some(reset) = args.heartbeat_reset {
+            self.heartbeat.reset_ms = reset;
+        }
+        if let Some(file) = &args.history_file {
+            self.history.file = Some(file.clone());
+        }
+        if let Some(level) = args.log_level {
+            self.log.level = level;
+        }
+        if let Some(file) = &args.log_file {
+            self.log.file = Some(file.clone());
+        }
+    }
+
+    /// Check that the settings make sense together
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        fn invalid(key: &'static str, reason: impl Into<String>) -> Result<(), ConfigError> {
+            Err(ConfigError::Invalid {
+                key,
+                reason: reason.into(),
+            })
+        }
+
+        if self.listen.is_empty() {
+            return invalid("listen", "at least one address is needed");
+        }
+        if self.listen.len() > MAX_LISTEN {
+            return invalid(
+                "listen",
+                format!("at most {} addresses allowed", MAX_LISTEN),
+            );
+        }
+        for (i, addr) in self.listen.iter().enumerate() {
+            if self.listen[..i].contains(addr) {
+                return invalid("listen", format!("`{}` is given twice", addr));
+            }
+        }
+        if self.unix_socket_mode > 0o777 {
+            return invalid(
+                "unix_socket_mode",
+                format!("{:o} isn't a permission mode", self.unix_socket_mode),
+            );
+        }
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(_), None) => return invalid("tls.key", "needed with `tls.cert`"),
+            (None, Some(_)) => return invalid("tls.cert", "needed with `tls.key`"),
+            (None, None) if self.listen.iter().any(ListenAddr::is_tls) => {
+                return invalid("tls.cert", "needed by the `tls:` listeners");
+            }
+            _ => {}
+        }
+        if let Some(motd) = &self.motd {
+            if motd.len() > u16::MAX as usize {
+                return invalid(
+                    "motd",
+                    format!("{} bytes long, at most {} allowed", motd.len(), u16::MAX),
+                );
+            }
+        }
+        if !self.accounts.allow_guests && self.accounts.file.is_none() {
+            return invalid("accounts.file", "needed when guests aren't allowed");
+        }
+        if self.limits.outbound_high_water < MIN_HIGH_WATER {
+            return invalid(
+                "limits.outbound_high_water",
+                format!("must be at least {} bytes", MIN_HIGH_WATER),
+            );
+        }
+        // The roster of every client must fit in a frame the client accepts and in the outbound queue
+        let max_roster = DEFAULT_MAX_PAYLOAD
+            .min(self.limits.outbound_high_water - FRAME_HEADER_SIZE)
+            - ROSTER_OVERHEAD;
+        let max_clients = max_roster / MAX_ROSTER_ENTRY;
+        if self.limits.max_clients == 0 || self.limits.max_clients > max_clients {
+            return invalid(
+                "limits.max_clients",
+                format!(
+                    "must be between 1 and {} for the roster to fit in a frame and in `limits.outbound_high_water`",
+                    max_clients
+                ),
+            );
+        }
+        if self.heartbeat.interval_ms == 0 {
+            return invalid("heartbeat.interval_ms", "must be at least 1");
+        }
+        if self.heartbeat.max_skipped == 0 {
+            return invalid("heartbeat.max_skipped", "must be at least 1");
+        }
+        // Clients would never time out if the count is reset before reaching the maximum
+        let timeout = self
+            .heartbeat
+            .interval_ms
+            .saturating_mul(self.heartbeat.max_skipped as u64);
+        if self.heartbeat.reset_ms <= timeout {
+            return invalid(
+                "heartbeat.reset_ms",
+                format!(
+                    "must be longer than interval_ms * max_skipped ({} ms)",
+                    timeout
+                ),
+            );
+        }
+        if self.history.page_limit == 0 {
+            return invalid("history.page_limit", "must be at least 1");
+        }
+        // A full page must fit in a frame the client accepts and in the outbound queue
+        let max_page = DEFAULT_MAX_PAYLOAD.min(self.limits.outbound_high_water - FRAME_HEADER_SIZE)
+            - PAGE_OVERHEAD;
+        let max_page_limit = max_page / MAX_MESSAGE_PACKET;
+        if self.history.page_limit as usize > max_page_limit {
+            return invalid(
+                "history.page_limit",
+                format!(
+                    "must be at most {} for a page to fit in a frame and in `limits.outbound_high_water`",
+                    max_page_limit
+                ),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parse_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value.trim_start_matches("0o"), 8)
+        .map_err(|e| format!("`{}` isn't an octal mode: {}", value, e))
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use common::{
+    frame::{Frame, FrameDecoder, FrameError, IntoFrame, FRAME_HEADER_SIZE},
+    Packet, ServerBroadcastMessageOwned,
+};
+
+// the size of the chunks the log is read in when it is opened
+const READ_CHUNK_SIZE: usize = 1 << 16;
+
+/// Where the broadcasted messages are kept
+pub(crate) trait HistoryStore {
+    /// Record a message that was just broadcasted
+    fn push(&mut self, message: ServerBroadcastMessageOwned) -> io::Result<()>;
+    /// The last `count` messages of `room`, oldest first
+    fn last(&self, room: &str, count: usize) -> Vec<ServerBroadcastMessageOwned>;
+    /// The last `limit` messages of `room` older than the message `before`, oldest first
+    fn before(
+        &self,
+        room: &str,
+        before: u64,
+        limit: usize,
+    ) -> io::Result<Vec<ServerBroadcastMessageOwned>>;
+    /// Id of the most recent message ever stored
+    fn last_id(&self) -> Option<u64>;
+
+    /// The page answering a history request, never more than `page_limit` messages.
+    ///
+    /// A page can be shorter than asked, only an empty one means there is nothing older.
+    fn page(
+        &self,
+        room: &str,
+        before: u64,
+        limit: u16,
+        page_limit: u16,
+    ) -> io::Result<Vec<ServerBroadcastMessageOwned>> {
+        self.before(room, before, limit.min(page_limit) as usize)
+    }
+}
+
+/// Keeps the last `capacity` messages of every room in memory
+#[derive(Debug)]
+pub(crate) struct MemoryHistory {
+    capacity: usize,
+    rooms: HashMap<String, VecDeque<ServerBroadcastMessageOwned>>,
+    // rooms that had to forget messages because they were full
+    evicted: HashSet<String>,
+    last_id: Option<u64>,
+}
+
+impl MemoryHistory {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            rooms: HashMap::new(),
+            evicted: HashSet::new(),
+            last_id: None,
+        }
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn push(&mut self, message: ServerBroadcastMessageOwned) -> io::Result<()> {
+        self.last_id = Some(message.message_id);
+        let messages = self.rooms.entry(message.room.clone()).or_default();
+        if messages.len() >= self.capacity {
+            messages.pop_front();
+            self.evicted.insert(message.room.clone());
+        }
+        if self.capacity > 0 {
+            messages.push_back(message);
+        }
+        Ok(())
+    }
+
+    fn last(&self, room: &str, count: usize) -> Vec<ServerBroadcastMessageOwned> {
+        match self.rooms.get(room) {
+            Some(messages) => messages
+                .iter()
+                .skip(messages.len().saturating_sub(count))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn before(
+        &self,
+        room: &str,
+        before: u64,
+        limit: usize,
+    ) -> io::Result<Vec<ServerBroadcastMessageOwned>> {
+        let messages = match self.rooms.get(room) {
+            Some(messages) => messages,
+            None => return Ok(Vec::new()),
+        };
+        let end = messages.partition_point(|m| m.message_id < before);
+        Ok(messages
+            .range(end.saturating_sub(limit)..end)
+            .cloned()
+            .collect())
+    }
+
+    fn last_id(&self) -> Option<u64> {
+        self.last_id
+    }
+}
+
+/// Append-only log of every message on disk, the recent ones are cached in memory.
+///
+/// The log is a sequence of `ServerBroadcastMessage` frames, exactly as they are sent.
+/// Only the offset of every frame is kept for the older messages, they are read back
+/// one frame at a time.
+#[derive(Debug)]
+pub(crate) struct FileHistory {
+    path: PathBuf,
+    file: File,
+    // id and offset in the log of every message, per room
+    index: HashMap<String, Vec<(u64, u64)>>,
+    cache: MemoryHistory,
+}
+
+impl FileHistory {
+    /// Open the log at `path`, creating it if needed, index it and load its tail in the cache
+    pub(crate) fn open(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut index: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        let mut cache = MemoryHistory::new(capacity);
+        let end = read_log(&mut file, |offset, message| {
+            index
+                .entry(message.room.clone())
+                .or_default()
+                .push((message.message_id, offset));
+            cache.push(message)
+        })?;
+        // A frame cut by a crash would swallow the ones appended after it
+        if end < file.metadata()?.len() {
+            file.set_len(end)?;
+        }
+        Ok(Self {
+            path,
+            file,
+            index,
+            cache,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl HistoryStore for FileHistory {
+    fn push(&mut self, message: ServerBroadcastMessageOwned) -> io::Result<()> {
+        // a whole frame at once, a crash can only truncate the last one
+        let mut frame = Vec::new();
+        message
+            .as_borrowed()
+            .write_frame_to_vec(&mut frame)
+            .map_err(io::Error::other)?;
+        let offset = self.file.metadata()?.len();
+        self.file.write_all(&frame)?;
+        self.index
+            .entry(message.room.clone())
+            .or_default()
+            .push((message.message_id, offset));
+        self.cache.push(message)
+    }
+
+    fn last(&self, room: &str, count: usize) -> Vec<ServerBroadcastMessageOwned> {
+        self.cache.last(room, count)
+    }
+
+    fn before(
+        &self,
+        room: &str,
+        before: u64,
+        limit: usize,
+    ) -> io::Result<Vec<ServerBroadcastMessageOwned>> {
+        let cached = self.cache.before(room, before, limit)?;
+        if cached.len() == limit || !self.cache.evicted.contains(room) {
+            return Ok(cached);
+        }
+        // The older messages are only on disk
+        let offsets = match self.index.get(room) {
+            Some(offsets) => offsets,
+            None => return Ok(cached),
+        };
+        let end = offsets.partition_point(|&(id, _)| id < before);
+        offsets[end.saturating_sub(limit)..end]
+            .iter()
+            .map(|&(_, offset)| read_message(&self.file, offset))
+            .collect()
+    }
+
+    fn last_id(&self) -> Option<u64> {
+        self.cache.last_id()
+    }
+}
+
+/// Give every message of a log with its offset to `f`, returns the end of the last whole frame
+fn read_log(
+    file: &mut File,
+    mut f: impl FnMut(u64, ServerBroadcastMessageOwned) -> io::Result<()>,
+) -> io::Result<u64> {
+    let mut frames = FrameDecoder::new();
+    let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+    let mut buffer = Vec::new();
+    // offset in the log of the start of `buffer`
+    let mut offset = 0u64;
+    let mut end = 0u64;
+    loop {
+        let n = match file.read(&mut chunk) {
+            Ok(0) => return Ok(end),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        buffer.extend_from_slice(&chunk[..n]);
+        let mut start = 0;
+        loop {
+            let skipped = frames.skipped();
+            let (consumed, frame) = frames.decode(&buffer[start..]);
+            // the frame comes after the garbage skipped to find it
+            let at = offset + (start + frames.skipped() - skipped) as u64;
+            start += consumed;
+            match frame {
+                Err(FrameError::Incomplete(_)) => break,
+                Ok(Frame::Packet(Packet::ServerBroadcastMessage(message))) => {
+                    f(at, message.into_owned())?
+                }
+                Ok(_) | Err(FrameError::Malformed(..)) => {}
+            }
+            end = offset + start as u64;
+        }
+        buffer.drain(..start);
+        offset += start as u64;
+    }
+}
+
+/// The message of the frame at `offset` in the log
+fn read_message(mut file: &File, offset: u64) -> io::Result<ServerBroadcastMessageOwned> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut frame = vec![0u8; FRAME_HEADER_SIZE];
+    file.read_exact(&mut frame)?;
+    let mut len = [0; 4];
+    len.copy_from_slice(&frame[2..FRAME_HEADER_SIZE]);
+    frame.resize(FRAME_HEADER_SIZE + u32::from_be_bytes(len) as usize, 0);
+    file.read_exact(&mut frame[FRAME_HEADER_SIZE..])?;
+    match FrameDecoder::new().decode(&frame).1 {
+        Ok(Frame::Packet(Packet::ServerBroadcastMessage(message))) => Ok(message.into_owned()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no message at offset {} of the log", offset),
+        )),
+    }
+}
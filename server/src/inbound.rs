@@ -0,0 +1,41 @@
+use std::io::{self, Read};
+
+use common::decoder::PacketDecoder;
+
+/// What is left to read on a client socket after `read_into`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReadStatus {
+    /// Everything was read, the socket would block
+    Drained,
+    /// The limit was reached, there may be more to read
+    Pending,
+    /// The client closed the connection
+    Closed,
+}
+
+/// Feed what the reader has to the decoder, until it would block or `limit` bytes were read.
+///
+/// The events are edge-triggered: a `Pending` reader must be read again without waiting
+/// for an event, a client sending without pause doesn't keep the loop to itself.
+pub(crate) fn read_into(
+    reader: &mut impl Read,
+    decoder: &mut PacketDecoder,
+    buffer: &mut [u8],
+    limit: usize,
+) -> io::Result<ReadStatus> {
+    let mut read = 0;
+    while read < limit {
+        let len = buffer.len().min(limit - read);
+        match reader.read(&mut buffer[..len]) {
+            Ok(0) => return Ok(ReadStatus::Closed),
+            Ok(n) => {
+                decoder.feed(&buffer[..n]);
+                read += n;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(ReadStatus::Drained),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(ReadStatus::Pending)
+}
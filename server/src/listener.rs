@@ -0,0 +1,300 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+use mio::{event::Source, net::TcpListener, net::TcpStream, Interest, Registry, Token};
+use rustls::ServerConfig;
+
+use crate::tls::TlsStream;
+
+/// Where the server accepts connections.
+///
+/// Written `127.0.0.1:8888`, `[::1]:8888`, `tls:0.0.0.0:8889` or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ListenAddr {
+    Tcp(SocketAddr),
+    Tls(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub(crate) fn is_tls(&self) -> bool {
+        matches!(self, ListenAddr::Tls(_))
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_tcp = |addr: &str| {
+            addr.parse::<SocketAddr>()
+                .map_err(|e| format!("`{}`: {}", s, e))
+        };
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("missing path of the Unix socket".to_string());
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tls:") {
+            parse_tcp(addr).map(ListenAddr::Tls)
+        } else {
+            parse_tcp(s).map(ListenAddr::Tcp)
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ListenAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Tls(addr) => write!(f, "tls:{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A socket accepting clients
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>),
+    #[cfg(unix)]
+    Unix(mio::net::UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Start listening, `mode` is the permissions given to a Unix socket and `tls` the
+    /// settings of a TLS listener
+    pub(crate) fn bind(
+        addr: &ListenAddr,
+        mode: u32,
+        tls: Option<&Arc<ServerConfig>>,
+    ) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => bind_tcp(*addr).map(Listener::Tcp),
+            ListenAddr::Tls(addr) => {
+                let tls = tls.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no TLS certificate given")
+                })?;
+                bind_tcp(*addr).map(|listener| Listener::Tls(listener, tls.clone()))
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+                // A socket left by a server that didn't stop cleanly
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if !metadata.file_type().is_socket() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            "the path exists and isn't a socket",
+                        ));
+                    }
+                    std::fs::remove_file(path)?;
+                }
+                let listener = mio::net::UnixListener::bind(path)?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                let _ = mode;
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unix sockets aren't supported on this platform",
+                ))
+            }
+        }
+    }
+
+    /// Accept a pending connection, with the address of the peer for the logs
+    pub(crate) fn accept(&self) -> io::Result<(Connection, String)> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(con, addr)| (Connection::Tcp(con), addr.to_string())),
+            Listener::Tls(listener, config) => {
+                let (con, addr) = listener.accept()?;
+                let stream = TlsStream::new(config.clone(), con)?;
+                Ok((Connection::Tls(Box::new(stream)), format!("tls:{}", addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => listener
+                .accept()
+                .map(|(con, _)| (Connection::Unix(con), format!("unix:{}", path.display()))),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "unknown address"),
+            },
+            Listener::Tls(listener, _) => match listener.local_addr() {
+                Ok(addr) => write!(f, "tls:{}", addr),
+                Err(_) => write!(f, "tls:unknown address"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => {
+                listener.register(registry, token, interests)
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => {
+                listener.reregister(registry, token, interests)
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => listener.deregister(registry),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.deregister(registry),
+        }
+    }
+}
+
+/// The socket of a client, whatever listener accepted it
+#[derive(Debug)]
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream>),
+    #[cfg(unix)]
+    Unix(mio::net::UnixStream),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(con) => con.read(buf),
+            Connection::Tls(con) => con.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(con) => con.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(con) => con.write(buf),
+            Connection::Tls(con) => con.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(con) => con.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(con) => con.flush(),
+            Connection::Tls(con) => con.flush(),
+            #[cfg(unix)]
+            Connection::Unix(con) => con.flush(),
+        }
+    }
+}
+
+impl Source for Connection {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Connection::Tcp(con) => con.register(registry, token, interests),
+            Connection::Tls(con) => con.socket().register(registry, token, interests),
+            #[cfg(unix)]
+            Connection::Unix(con) => con.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Connection::Tcp(con) => con.reregister(registry, token, interests),
+            Connection::Tls(con) => con.socket().reregister(registry, token, interests),
+            #[cfg(unix)]
+            Connection::Unix(con) => con.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Connection::Tcp(con) => con.deregister(registry),
+            Connection::Tls(con) => con.socket().deregister(registry),
+            #[cfg(unix)]
+            Connection::Unix(con) => con.deregister(registry),
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // An IPv6 listener only takes IPv6 clients so `0.0.0.0` and `[::]` can both be used
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
@@ -1,92 +1,361 @@
 #![warn(clippy::all)]
+extern crate clap;
 extern crate common;
-extern crate crossbeam_channel;
 #[macro_use]
 extern crate log;
 extern crate ctrlc;
+extern crate mio;
 extern crate rand;
+extern crate rustls;
+extern crate serde;
 extern crate simplelog;
+#[macro_use]
+extern crate thiserror;
+extern crate toml;
+
+mod accounts;
+mod broadcast;
+mod config;
+mod history;
+mod inbound;
+mod listener;
+mod outbound;
+mod rooms;
+mod tls;
+mod usernames;
+
+#[cfg(test)]
+mod tests;
+
 use std::{
     collections::{HashMap, HashSet},
-    io::prelude::*,
-    net,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use common::{parser::FromBytes, serializer::IntoBytes, Packet, PacketOwned};
+use accounts::Accounts;
+use broadcast::{Audience, Broadcasts};
+use clap::Parser;
+use common::{
+    auth,
+    decoder::PacketDecoder,
+    frame::IntoFrame,
+    protocol::{self, Capabilities, ReasonCode},
+    validation, PacketOwned,
+};
+use config::{Args, Config};
+use history::{FileHistory, HistoryStore, MemoryHistory};
+use inbound::ReadStatus;
+use listener::{Connection, Listener};
+use mio::{Events, Interest, Poll, Token, Waker};
+use outbound::OutboundQueue;
+use rooms::Rooms;
+use usernames::Usernames;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum ConnectionStatus {
     WaitingForClientVerification = 0,
-    SentServerConfirmation = 1,
-    HandShakeDone = 2,
+    WaitingForAuthentication = 1,
+    SentServerConfirmation = 2,
+    HandShakeDone = 3,
 }
 
 type ClientID = u32;
 struct Client {
-    pub(crate) con: net::TcpStream,
+    pub(crate) con: Connection,
     pub(crate) id: ClientID,
     pub(crate) connection_status: ConnectionStatus,
     pub(crate) heartbeat_skipped: u8,
-    pub(crate) lastheart_beat: std::time::Instant,
+    pub(crate) lastheart_beat: Instant,
+    // when the last heartbeat request was sent
+    pub(crate) lastheart_request: Instant,
     pub(crate) magic: u32,
     pub(crate) username: String,
+    pub(crate) protocol_version: u16,
+    pub(crate) capabilities: Capabilities,
+    // nonce of the challenge sent to log in to an account
+    pub(crate) challenge: Option<[u8; auth::NONCE_LEN]>,
+    pub(crate) decoder: PacketDecoder,
+    // frames waiting for the socket to be writable
+    pub(crate) outbound: OutboundQueue,
+    // scratch buffer the packets sent to this client only are encoded in
+    pub(crate) encoded: Vec<u8>,
+    // reason given by the client when it quit, announced to the others
+    pub(crate) quit_message: String,
 }
 
 impl Client {
+    /// Write a frame to the client, queuing what the socket doesn't accept right now.
+    ///
+    /// A client with too much data waiting is told why and gets an error, it must be dropped.
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if !self.outbound.send(&mut self.con, bytes)? {
+            warn!(
+                "Client `{}` is too slow, {} bytes are waiting",
+                self.id,
+                self.outbound.len()
+            );
+            self.outbound.discard_unsent();
+            let mut frame = Vec::new();
+            let disconnect = common::ServerDisconnect::new(
+                ReasonCode::TooSlow.code(),
+                "Too many messages waiting to be read",
+            );
+            if let Some(Ok(_)) = built(disconnect).map(|p| p.write_frame_to_vec(&mut frame)) {
+                self.outbound.force_push(&frame);
+                let _ = self.flush();
+            }
+            return Err(std::io::Error::other(
+                "outbound queue is over the high-water mark",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encode a packet and send it to the client, see `send`
+    fn send_packet(&mut self, packet: &impl IntoFrame) -> std::io::Result<()> {
+        // the buffer is kept from one packet to the next
+        let mut frame = std::mem::take(&mut self.encoded);
+        frame.clear();
+        let result = packet
+            .write_frame_to_vec(&mut frame)
+            .map_err(std::io::Error::other)
+            .and_then(|_| self.send(&frame));
+        self.encoded = frame;
+        result
+    }
+
+    /// Write the queued frames until the socket would block
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.outbound.flush(&mut self.con)
+    }
+
+    /// Send the last `count` messages of a room
+    fn send_history(
+        &mut self,
+        history: &dyn HistoryStore,
+        room: &str,
+        count: usize,
+    ) -> std::io::Result<()> {
+        for message in history.last(room, count) {
+            self.send_packet(&message.as_borrowed())?;
+        }
+        Ok(())
+    }
+
+    /// Check that a packet comes from a registered client with the right credentials
+    fn check_registered(
+        &self,
+        client_id: ClientID,
+        magic: u32,
+    ) -> Result<(), (ReasonCode, &'static str)> {
+        if self.connection_status != ConnectionStatus::HandShakeDone {
+            return Err((ReasonCode::WrongState, "Registration isn't done"));
+        }
+        if self.id != client_id {
+            return Err((ReasonCode::WrongId, "Wrong client id"));
+        }
+        if self.magic != magic {
+            return Err((ReasonCode::WrongMagic, "Wrong magic"));
+        }
+        Ok(())
+    }
+
     fn send_hearbeat(&mut self) -> Result<(), std::io::Error> {
         let packet = common::HeartBeatRequest {};
-        let bytes = packet.unwrap_bytes();
-        self.con.write(&bytes)?;
-        Ok(())
+        self.send_packet(&packet)
     }
 
     fn send_registration_confirmation(&mut self) -> Result<(), std::io::Error> {
         let packet = common::ServerRegistrationConfirmation {
             client_id: self.id,
             magic: self.magic,
+            protocol_version: self.protocol_version,
+            capabilities: self.capabilities.bits(),
         };
-        let bytes = packet.unwrap_bytes();
-        self.con.write(&bytes)?;
-        Ok(())
+        self.send_packet(&packet)
+    }
+
+    fn send_auth_challenge(&mut self, account: &accounts::Account) -> std::io::Result<()> {
+        let mut nonce = [0u8; auth::NONCE_LEN];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut nonce);
+        self.challenge = Some(nonce);
+        let packet = common::ServerAuthChallenge {
+            salt: account.salt,
+            iterations: account.iterations,
+            nonce,
+        };
+        self.send_packet(&packet)
+    }
+
+    fn send_registration_refusal(&mut self, reason: &str) -> Result<(), std::io::Error> {
+        let packet = common::ServerRegistrationRefusal::new(
+            protocol::PROTOCOL_VERSION,
+            protocol::MIN_PROTOCOL_VERSION,
+            reason,
+        )
+        .map_err(std::io::Error::other)?;
+        self.send_packet(&packet)
+    }
+
+    fn send_error(&mut self, code: ReasonCode, message: &str) -> Result<(), std::io::Error> {
+        let packet =
+            common::ServerError::new(code.code(), message).map_err(std::io::Error::other)?;
+        self.send_packet(&packet)
+    }
+
+    fn send_disconnect(&mut self, code: ReasonCode, message: &str) -> Result<(), std::io::Error> {
+        let packet =
+            common::ServerDisconnect::new(code.code(), message).map_err(std::io::Error::other)?;
+        self.send_packet(&packet)
+    }
+
+    fn send_motd(&mut self, motd: &str) -> Result<(), std::io::Error> {
+        let packet = common::ServerMotd::new(motd).map_err(std::io::Error::other)?;
+        self.send_packet(&packet)
+    }
+
+    /// Tell the client why it is about to be dropped
+    fn disconnect(&mut self, code: ReasonCode, message: &str) {
+        if let Err(e) = self.send_disconnect(code, message) {
+            debug!("Unable to send disconnect to client `{}`: {}", self.id, e);
+        }
     }
 }
 
-// the message type for the message broadcast queue
-type Message = Vec<u8>;
+// the size of the buffer used for each read on a client socket
+const READ_BUFFER_SIZE: usize = 4096;
+// the most bytes read from a client before the others get their turn
+const READ_LIMIT: usize = 16 * READ_BUFFER_SIZE;
 
-// the maximum size of a packet in bytes;
-// Currently the largest packet is commom::ServerBroadcastMessage
-const PACKET_MAX_SIZE: usize = 3 + 4 + 4 + 1 + u8::MAX as usize + 2 + u16::MAX as usize;
+// the token used to wake the loop up when stopping
+const WAKER: Token = Token(0);
+// the token of the first listening socket, the next ones count up
+const FIRST_LISTENER: usize = 1;
+// clients use their id as token, no id is below this one
+const FIRST_CLIENT: usize = FIRST_LISTENER + config::MAX_LISTEN;
+// the longest time the loop sleeps without checking the heartbeats
+const TICK: Duration = Duration::from_millis(500);
 
-const MAX_HB_SKIP: u8 = 5;
-const HB_SKIP_REST: std::time::Duration = std::time::Duration::from_secs(30);
-const HB_REQUEST_TIME: std::time::Duration = std::time::Duration::from_secs(2);
 static STOPPING: AtomicBool = AtomicBool::new(false);
 
 fn main() {
-    println!();
-    simplelog::TermLogger::init(
-        simplelog::LevelFilter::max(),
-        Default::default(),
-        simplelog::TerminalMode::Mixed,
-    )
-    .unwrap();
-
-    let _ = ctrlc::set_handler(|| {
+    let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if args.check {
+        println!("Configuration is valid");
+        return;
+    }
+    if let Some(username) = &args.add_account {
+        if let Err(e) = add_account(&config, username) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Err(e) = init_logger(&config.log) {
+        eprintln!("Error: unable to set up the logs: {}", e);
+        std::process::exit(2);
+    }
+
+    let mut poll = match Poll::new() {
+        Ok(poll) => poll,
+        Err(e) => {
+            error!("Unable to create the event loop: {}", e);
+            return;
+        }
+    };
+    let mut events = Events::with_capacity(128);
+
+    let waker = match Waker::new(poll.registry(), WAKER) {
+        Ok(waker) => Arc::new(waker),
+        Err(e) => {
+            error!("Unable to create the event loop: {}", e);
+            return;
+        }
+    };
+    let _ = ctrlc::set_handler(move || {
         info!("Stopping the server!");
         STOPPING.store(true, Ordering::SeqCst);
+        let _ = waker.wake();
     })
     .map_err(|e| error!("Error when setting shutdown handler: {}", e));
 
-    let (_thread_handle, recv_tcp) = {
-        let (h, rx) = generate_connection_handler("127.0.0.1:8888");
-        (std::thread::spawn(h), rx)
+    let tls_config = match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => match tls::server_config(cert, key) {
+            Ok(tls_config) => Some(tls_config),
+            Err(e) => {
+                error!("Unable to load the TLS certificate: {}", e);
+                return;
+            }
+        },
+        _ => None,
     };
 
+    let accounts = match &config.accounts.file {
+        Some(path) => match Accounts::open(path) {
+            Ok(accounts) => {
+                info!(
+                    "{} accounts loaded from `{}`",
+                    accounts.len(),
+                    path.display()
+                );
+                Some(accounts)
+            }
+            Err(e) => {
+                error!("Unable to load the accounts: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    if !config.accounts.allow_guests {
+        info!("Only the registered usernames can join");
+    }
+
+    let mut listeners: Vec<Listener> = Vec::with_capacity(config.listen.len());
+    for addr in &config.listen {
+        let mut listener = match Listener::bind(addr, config.unix_socket_mode, tls_config.as_ref())
+        {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Error when binding to `{}`: {}", addr, e);
+                return;
+            }
+        };
+        let token = Token(FIRST_LISTENER + listeners.len());
+        if let Err(e) = poll
+            .registry()
+            .register(&mut listener, token, Interest::READABLE)
+        {
+            error!("Unable to listen for connections on `{}`: {}", addr, e);
+            return;
+        }
+        info!("Listening on {}", listener);
+        listeners.push(listener);
+    }
+
+    debug!(
+        "Clients can have {} bytes waiting to be sent",
+        config.limits.outbound_high_water
+    );
+    let hb_request_time = config.heartbeat.interval();
+    let hb_skip_rest = config.heartbeat.reset();
+    // the loop must wake up often enough to ask for the heartbeats in time
+    let tick = TICK.min(hb_request_time);
+
     // The last "skipped hb" clear
-    let mut last_clear = std::time::Instant::now();
+    let mut last_clear = Instant::now();
 
     // List of all clients with default capacity of 100;
     let mut clients: HashMap<ClientID, Client> = HashMap::with_capacity(10);
@@ -98,66 +367,144 @@ fn main() {
     let mut to_drop: HashSet<ClientID> = HashSet::with_capacity(10);
 
     // A buffer to read packets;
-    let mut packet_buffer: Vec<u8> = Vec::with_capacity(PACKET_MAX_SIZE);
+    let mut read_buffer = [0u8; READ_BUFFER_SIZE];
 
-    // The list of client in need of an heartbeat
-    let mut need_hearbeat: Vec<ClientID> = Vec::with_capacity(10);
+    // The list of client with data to read
+    let mut readable: Vec<ClientID> = Vec::with_capacity(10);
+    // The clients that reached the read limit, read again in the next loop
+    let mut unread: Vec<ClientID> = Vec::with_capacity(10);
 
     // List of all message to broadcast
-    let mut message_to_broadcast: Vec<Message> = Vec::with_capacity(10);
+    let mut broadcasts = Broadcasts::new();
+
+    // Members of every room
+    let mut rooms = Rooms::new();
+
+    // Username of every client past the registration request
+    let mut usernames = Usernames::new();
+
+    // Private messages to route once every packet is processed, with their sender
+    let mut private_messages: Vec<(ClientID, common::ClientPrivateMessageOwned)> =
+        Vec::with_capacity(10);
+
+    // Every message broadcasted in a room
+    let mut history: Box<dyn HistoryStore> = match &config.history.file {
+        Some(path) => match FileHistory::open(path, config.history.capacity) {
+            Ok(history) => {
+                info!("History is stored in `{}`", history.path().display());
+                Box::new(history)
+            }
+            Err(e) => {
+                error!("Unable to open the history log: {}", e);
+                return;
+            }
+        },
+        None => Box::new(MemoryHistory::new(config.history.capacity)),
+    };
+
+    // Id of the next broadcasted message, never reused
+    let mut next_message_id: u64 = history.last_id().map(|id| id + 1).unwrap_or(1);
+
+    // The list of client waiting for the roster
+    let mut roster_requests: Vec<ClientID> = Vec::with_capacity(10);
 
     'mainloop: loop {
-        let need_clear_hb_skip = last_clear + HB_SKIP_REST > std::time::Instant::now();
+        // Sleep until a socket is ready, or it is time to check the heartbeats
+        // Departures queued by the last loop are sent right away, and unread data read
+        let timeout = if broadcasts.is_empty() && unread.is_empty() {
+            tick
+        } else {
+            Duration::from_millis(0)
+        };
+        if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                error!("Error when waiting for events: {}", e);
+                break 'mainloop;
+            }
+        }
+        let need_clear_hb_skip = last_clear.elapsed() >= hb_skip_rest;
         // Clearing the per loop list;
         packets.clear();
         to_drop.clear();
-        need_hearbeat.clear();
-        message_to_broadcast.clear();
-
-        // Check for new client
-        while let Ok(new_client) = recv_tcp.try_recv() {
-            // Generate a id for the new client
-            let mut new_id: ClientID = generate_client_id();
-            // if the id already exist, generate a new one
-            while clients.get(&new_id).is_some() {
-                new_id = generate_client_id();
+        readable.clear();
+        readable.append(&mut unread);
+        private_messages.clear();
+        roster_requests.clear();
+
+        for event in events.iter() {
+            match event.token() {
+                WAKER => {}
+                Token(token) if token < FIRST_CLIENT => {
+                    if let Some(listener) = listeners.get(token - FIRST_LISTENER) {
+                        accept_clients(listener, poll.registry(), &mut clients, &config.limits);
+                    }
+                }
+                Token(token) => {
+                    let client_id = token as ClientID;
+                    if (event.is_readable() || event.is_read_closed() || event.is_error())
+                        && !readable.contains(&client_id)
+                    {
+                        readable.push(client_id);
+                    }
+                    if event.is_writable() {
+                        if let Some(client) = clients.get_mut(&client_id) {
+                            if let Err(e) = client.flush() {
+                                error!(
+                                    "Error when sending packet to client `{}`: {}",
+                                    client_id, e
+                                );
+                                to_drop.insert(client_id);
+                            }
+                        }
+                    }
+                }
             }
-            let magic = generate_client_magic();
-            // Add new client to the clients hashmap
-            clients.insert(
-                new_id,
-                Client {
-                    con: new_client,
-                    id: new_id,
-                    connection_status: ConnectionStatus::WaitingForClientVerification,
-                    heartbeat_skipped: 0,
-                    lastheart_beat: std::time::Instant::now(),
-                    magic,
-                    username: String::new(),
-                },
-            );
         }
-        // get a list of all the packets that the clients send
-        for (&client_id, client) in clients.iter_mut() {
-            if need_clear_hb_skip {
+
+        if need_clear_hb_skip {
+            for client in clients.values_mut() {
                 client.heartbeat_skipped = 0;
             }
-            packet_buffer.clear();
-            client
-                .con
-                .read_to_end(&mut packet_buffer)
-                .map_err(|err| {
-                    error!(
-                        "Error when reading data from client `{}`: {}",
-                        client_id, err
-                    );
+        }
+
+        // get a list of all the packets that the clients send
+        for &client_id in &readable {
+            let client = match clients.get_mut(&client_id) {
+                Some(client) => client,
+                None => continue,
+            };
+            match inbound::read_into(
+                &mut client.con,
+                &mut client.decoder,
+                &mut read_buffer,
+                READ_LIMIT,
+            ) {
+                Ok(ReadStatus::Drained) => {}
+                Ok(ReadStatus::Pending) => unread.push(client_id),
+                Ok(ReadStatus::Closed) => {
+                    debug!("Client `{}` closed the connection", client_id);
                     to_drop.insert(client_id);
-                })
-                .unwrap_or(0);
-            let mut bytes = packet_buffer.as_slice();
-            while let Ok((new_bytes, packet)) = Packet::from_bytes(bytes) {
-                bytes = new_bytes;
-                packets.push((client_id, packet.into_owned()));
+                }
+                Err(e) => {
+                    error!("Error when reading data from client `{}`: {}", client_id, e);
+                    to_drop.insert(client_id);
+                }
+            }
+            loop {
+                match client.decoder.next_packet() {
+                    Ok(Some(packet)) => packets.push((client_id, packet)),
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Client `{}` sent a bad packet: {}", client_id, e);
+                        if let Err(e) = client.send_error(
+                            ReasonCode::MalformedPacket,
+                            &format!("Malformed packet: {}", e),
+                        ) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                        }
+                    }
+                }
             }
         }
 
@@ -174,14 +521,157 @@ fn main() {
                     // If the client is already registered, wrong packet => dropped
                     if client.connection_status != ConnectionStatus::WaitingForClientVerification {
                         info!("Client `{}` sent wrong packet", client_id);
+                        client.disconnect(ReasonCode::WrongState, "Already registered");
                         to_drop.insert(client_id);
                         continue;
                     }
+                    // Check that we can speak the same protocol
+                    let (version, capabilities) = match protocol::negotiate(
+                        packet.protocol_version,
+                        Capabilities(packet.capabilities),
+                    ) {
+                        Ok(negotiated) => negotiated,
+                        Err(e) => {
+                            info!("Client `{}` refused: {}", client_id, e);
+                            if let Err(e) = client.send_registration_refusal(&e.to_string()) {
+                                error!(
+                                    "Error when sending packet to client `{}`: {}",
+                                    client_id, e
+                                );
+                            }
+                            to_drop.insert(client_id);
+                            continue;
+                        }
+                    };
+                    debug!(
+                        "Client `{}` speaks protocol v{} with capabilities {:#x}",
+                        client_id,
+                        version,
+                        capabilities.bits()
+                    );
+                    let username = match validation::username(&packet.username) {
+                        Ok(username) => username,
+                        Err(e) => {
+                            let reason = format!("Invalid username: {}", e);
+                            info!("Client `{}` refused: {}", client_id, reason);
+                            if let Err(e) = client.send_registration_refusal(&reason) {
+                                error!(
+                                    "Error when sending packet to client `{}`: {}",
+                                    client_id, e
+                                );
+                            }
+                            to_drop.insert(client_id);
+                            continue;
+                        }
+                    };
                     // Set the values
-                    client.username = packet.username;
-                    client.connection_status = ConnectionStatus::SentServerConfirmation;
+                    client.username = username;
+                    client.protocol_version = version;
+                    client.capabilities = capabilities;
+
+                    // A registered username needs its password, the others are guests
+                    let account = accounts.as_ref().and_then(|a| a.get(&client.username));
+                    let refusal = match account {
+                        Some(_) if version < protocol::AUTH_PROTOCOL_VERSION => Some(format!(
+                            "`{}` has a password, protocol version {} is needed to log in",
+                            client.username,
+                            protocol::AUTH_PROTOCOL_VERSION
+                        )),
+                        None if !config.accounts.allow_guests => Some(format!(
+                            "`{}` isn't a registered username and guests aren't allowed",
+                            client.username
+                        )),
+                        _ => None,
+                    };
+                    if let Some(reason) = refusal {
+                        info!("Client `{}` refused: {}", client_id, reason);
+                        if let Err(e) = client.send_registration_refusal(&reason) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                        }
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    // Nobody else can have the same username, the username of an account is
+                    // only claimed with its password: else anyone could keep its owner out
+                    if account.is_none() {
+                        if let Err(e) = usernames.claim(&client.username, client_id) {
+                            info!("Client `{}` refused: {}", client_id, e);
+                            if let Err(e) = client.send_registration_refusal(&e.to_string()) {
+                                error!(
+                                    "Error when sending packet to client `{}`: {}",
+                                    client_id, e
+                                );
+                            }
+                            to_drop.insert(client_id);
+                            continue;
+                        }
+                    }
 
                     // Sending him the next registration packet
+                    let sent = match account {
+                        Some(account) => {
+                            client.connection_status = ConnectionStatus::WaitingForAuthentication;
+                            client.send_auth_challenge(account)
+                        }
+                        None => {
+                            client.connection_status = ConnectionStatus::SentServerConfirmation;
+                            client.send_registration_confirmation()
+                        }
+                    };
+                    if let Err(e) = sent {
+                        error!("Error when sending packet to client `{}`: {}", client_id, e);
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                }
+                PacketOwned::ClientAuthResponse(packet) => {
+                    let nonce = match client.challenge.take() {
+                        Some(nonce)
+                            if client.connection_status
+                                == ConnectionStatus::WaitingForAuthentication =>
+                        {
+                            nonce
+                        }
+                        _ => {
+                            info!("Client `{}` sent wrong packet", client_id);
+                            client.disconnect(ReasonCode::WrongState, "No password was asked for");
+                            to_drop.insert(client_id);
+                            continue;
+                        }
+                    };
+                    // The account can't be gone, the file is only read at start
+                    let verified = accounts
+                        .as_ref()
+                        .and_then(|a| a.get(&client.username))
+                        .is_some_and(|account| {
+                            auth::verify_proof(
+                                &account.stored_key,
+                                &client.username,
+                                &nonce,
+                                &packet.proof,
+                            )
+                        });
+                    if !verified {
+                        info!(
+                            "Client `{}` gave a wrong password for `{}`",
+                            client_id, client.username
+                        );
+                        if let Err(e) = client.send_registration_refusal("Wrong password") {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                        }
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    if let Err(e) = usernames.claim(&client.username, client_id) {
+                        info!("Client `{}` refused: {}", client_id, e);
+                        if let Err(e) = client.send_registration_refusal(&e.to_string()) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                        }
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    info!("Client `{}` logged in as `{}`", client_id, client.username);
+                    client.connection_status = ConnectionStatus::SentServerConfirmation;
                     if let Err(e) = client.send_registration_confirmation() {
                         error!("Error when sending packet to client `{}`: {}", client_id, e);
                         to_drop.insert(client_id);
@@ -192,126 +682,667 @@ fn main() {
                     // If the client is already registered, wrong packet => dropped
                     if client.connection_status != ConnectionStatus::SentServerConfirmation {
                         info!("Client `{}` sent wrong packet", client_id);
+                        client.disconnect(
+                            ReasonCode::WrongState,
+                            "Registration end sent at the wrong time",
+                        );
                         to_drop.insert(client_id);
                         continue;
                     }
                     // Checking if the client sent the correct id
                     if client.id != packet.client_id {
                         debug!("Client `{}` sent wrong id", client_id);
+                        client.disconnect(ReasonCode::WrongId, "Wrong client id");
                         to_drop.insert(client_id);
                         continue;
                     }
                     // Checking if the client sent the correct magic
                     if client.magic != packet.magic {
                         debug!("Client `{}` sent wrong magic", client_id);
+                        client.disconnect(ReasonCode::WrongMagic, "Wrong magic");
                         to_drop.insert(client_id);
                         continue;
                     }
 
                     client.connection_status = ConnectionStatus::HandShakeDone;
+
+                    if let Some(motd) = &config.motd {
+                        if let Err(e) = client.send_motd(motd) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                            continue;
+                        }
+                    }
+
+                    if let Some(joined_packet) =
+                        built(common::ServerUserJoined::new(client.id, &client.username))
+                    {
+                        broadcasts.push(Audience::Everyone, &joined_packet);
+                    }
+
+                    rooms.join(protocol::DEFAULT_ROOM, client_id);
+                    if let Err(e) = client.send_history(
+                        history.as_ref(),
+                        protocol::DEFAULT_ROOM,
+                        config.history.replay,
+                    ) {
+                        error!("Error when sending packet to client `{}`: {}", client_id, e);
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    if let Some(joined_packet) = built(common::ServerRoomJoined::new(
+                        client.id,
+                        &client.username,
+                        protocol::DEFAULT_ROOM,
+                    )) {
+                        broadcasts.push(
+                            Audience::Room(protocol::DEFAULT_ROOM.to_string()),
+                            &joined_packet,
+                        );
+                    }
                 }
                 PacketOwned::ClientSendMessage(packet) => {
-                    // If the client is already registered, wrong packet => dropped
-                    if client.connection_status != ConnectionStatus::HandShakeDone {
-                        info!("Client `{}` sent wrong packet", client_id);
+                    // If the client isn't registered, wrong packet => dropped
+                    if let Err((code, reason)) =
+                        client.check_registered(packet.client_id, packet.magic)
+                    {
+                        info!("Client `{}` sent wrong packet: {}", client_id, reason);
+                        client.disconnect(code, reason);
                         to_drop.insert(client_id);
                         continue;
                     }
-                    // Checking if the client sent the correct id
-                    if client.id != packet.client_id {
-                        debug!("Client `{}` sent wrong id", client_id);
+                    // Only members can talk in a room
+                    if !rooms.is_member(&packet.room, client_id) {
+                        if let Err(e) = client.send_error(
+                            ReasonCode::NotInRoom,
+                            &format!("You aren't in room `{}`", packet.room),
+                        ) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                        }
+                        continue;
+                    }
+                    // Nothing that could mess with the terminals of the others
+                    let message = match validation::message(&packet.message) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            if let Err(e) = client.send_error(
+                                ReasonCode::InvalidMessage,
+                                &format!("Invalid message: {}", e),
+                            ) {
+                                error!(
+                                    "Error when sending packet to client `{}`: {}",
+                                    client_id, e
+                                );
+                                to_drop.insert(client_id);
+                            }
+                            continue;
+                        }
+                    };
+                    // Construct the "Message" to broadcast to other clients
+                    let message_packet = match built(common::ServerBroadcastMessage::new(
+                        next_message_id,
+                        unix_timestamp(),
+                        client.id,
+                        &packet.room,
+                        &client.username,
+                        &message,
+                    )) {
+                        Some(message_packet) => message_packet,
+                        None => continue,
+                    };
+                    if let Err(e) = history.push(message_packet.into_owned()) {
+                        error!("Unable to store message `{}`: {}", next_message_id, e);
+                    }
+
+                    next_message_id += 1;
+                    broadcasts.push(Audience::Room(packet.room.clone()), &message_packet);
+                }
+                PacketOwned::ClientJoinRoom(packet) => {
+                    if let Err((code, reason)) =
+                        client.check_registered(packet.client_id, packet.magic)
+                    {
+                        info!("Client `{}` sent wrong packet: {}", client_id, reason);
+                        client.disconnect(code, reason);
                         to_drop.insert(client_id);
                         continue;
                     }
-                    // Checking if the client sent the correct magic
-                    if client.magic != packet.magic {
-                        debug!("Client `{}` sent wrong magic", client_id);
+                    if !rooms::is_valid_room_name(&packet.room) {
+                        if let Err(e) = client.send_error(
+                            ReasonCode::InvalidRoom,
+                            &format!("`{}` isn't a valid room name", packet.room),
+                        ) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                        }
+                        continue;
+                    }
+                    // Already a member, only the client is told it is in
+                    if !rooms.join(&packet.room, client_id) {
+                        let username = client.username.clone();
+                        let sent =
+                            common::ServerRoomJoined::new(client.id, &username, &packet.room)
+                                .map_err(std::io::Error::other)
+                                .and_then(|joined_packet| client.send_packet(&joined_packet));
+                        if let Err(e) = sent {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                        }
+                        continue;
+                    }
+                    if let Err(e) =
+                        client.send_history(history.as_ref(), &packet.room, config.history.replay)
+                    {
+                        error!("Error when sending packet to client `{}`: {}", client_id, e);
                         to_drop.insert(client_id);
                         continue;
                     }
-                    // Construct the "Message" to broadcast to other clients
-                    let message_packet = common::ServerBroadcastMessage {
-                        user_id: client.id,
-                        username: client.username.as_str(),
-                        username_len: client.username.len() as u8,
-                        message: packet.message.as_str(),
-                        message_len: packet.message.len() as u16,
+                    debug!("Client `{}` joined `{}`", client_id, packet.room);
+                    if let Some(joined_packet) = built(common::ServerRoomJoined::new(
+                        client.id,
+                        &client.username,
+                        &packet.room,
+                    )) {
+                        broadcasts.push(Audience::Room(packet.room.clone()), &joined_packet);
+                    }
+                }
+                PacketOwned::ClientPartRoom(packet) => {
+                    if let Err((code, reason)) =
+                        client.check_registered(packet.client_id, packet.magic)
+                    {
+                        info!("Client `{}` sent wrong packet: {}", client_id, reason);
+                        client.disconnect(code, reason);
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    if !rooms.is_member(&packet.room, client_id) {
+                        if let Err(e) = client.send_error(
+                            ReasonCode::NotInRoom,
+                            &format!("You aren't in room `{}`", packet.room),
+                        ) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                        }
+                        continue;
+                    }
+                    debug!("Client `{}` parted `{}`", client_id, packet.room);
+                    // The leaving client gets it too, it confirms the part
+                    let username = client.username.clone();
+                    rooms.part(&packet.room, client_id);
+                    let parted_packet = match built(common::ServerRoomParted::new(
+                        client.id,
+                        &username,
+                        &packet.room,
+                    )) {
+                        Some(parted_packet) => parted_packet,
+                        None => continue,
                     };
-
-                    message_to_broadcast.push(message_packet.unwrap_bytes());
+                    if let Err(e) = client.send_packet(&parted_packet) {
+                        error!("Error when sending packet to client `{}`: {}", client_id, e);
+                        to_drop.insert(client_id);
+                    }
+                    broadcasts.push(Audience::Room(packet.room.clone()), &parted_packet);
+                }
+                PacketOwned::ClientQuit(packet) => {
+                    // The client is leaving anyway, only announce registered ones
+                    to_drop.insert(client_id);
+                    if client.connection_status != ConnectionStatus::HandShakeDone
+                        || client.id != packet.client_id
+                        || client.magic != packet.magic
+                    {
+                        debug!("Client `{}` quit before registration", client_id);
+                        continue;
+                    }
+                    info!("Client `{}` quit: {:?}", client_id, packet.message);
+                    // Announced when the client is dropped
+                    client.quit_message = validation::message(&packet.message).unwrap_or_default();
+                }
+                PacketOwned::ClientHistoryRequest(packet) => {
+                    if let Err((code, reason)) =
+                        client.check_registered(packet.client_id, packet.magic)
+                    {
+                        info!("Client `{}` sent wrong packet: {}", client_id, reason);
+                        client.disconnect(code, reason);
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    if !rooms.is_member(&packet.room, client_id) {
+                        if let Err(e) = client.send_error(
+                            ReasonCode::NotInRoom,
+                            &format!("You aren't in room `{}`", packet.room),
+                        ) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                        }
+                        continue;
+                    }
+                    let messages = match history.page(
+                        &packet.room,
+                        packet.before,
+                        packet.limit,
+                        config.history.page_limit,
+                    ) {
+                        Ok(messages) => messages,
+                        Err(e) => {
+                            error!("Unable to read the history of `{}`: {}", packet.room, e);
+                            Vec::new()
+                        }
+                    };
+                    let sent = common::ServerHistory::new(
+                        &packet.room,
+                        messages.iter().map(|m| m.as_borrowed()).collect(),
+                    )
+                    .map_err(std::io::Error::other)
+                    .and_then(|history_packet| client.send_packet(&history_packet));
+                    if let Err(e) = sent {
+                        error!("Error when sending packet to client `{}`: {}", client_id, e);
+                        to_drop.insert(client_id);
+                    }
+                }
+                PacketOwned::ClientRosterRequest(packet) => {
+                    if let Err((code, reason)) =
+                        client.check_registered(packet.client_id, packet.magic)
+                    {
+                        info!("Client `{}` sent wrong packet: {}", client_id, reason);
+                        client.disconnect(code, reason);
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    roster_requests.push(client_id);
                 }
                 PacketOwned::HeartBeatSend(_) => {
                     // If the client is already registered, wrong packet => dropped
                     if client.connection_status != ConnectionStatus::HandShakeDone {
                         info!("Client `{}` sent wrong packet", client_id);
+                        client.disconnect(ReasonCode::WrongState, "Registration isn't done");
                         to_drop.insert(client_id);
                         continue;
                     }
 
-                    client.lastheart_beat = std::time::Instant::now();
+                    client.lastheart_beat = Instant::now();
                     trace!("Got HeartBeat from client `{}`", client_id);
                 }
+                PacketOwned::ClientChangeNick(packet) => {
+                    if let Err((code, reason)) =
+                        client.check_registered(packet.client_id, packet.magic)
+                    {
+                        info!("Client `{}` sent wrong packet: {}", client_id, reason);
+                        client.disconnect(code, reason);
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    let username = match validation::username(&packet.username) {
+                        Ok(username) => username,
+                        Err(e) => {
+                            if let Err(e) = client.send_error(
+                                ReasonCode::InvalidUsername,
+                                &format!("Invalid username: {}", e),
+                            ) {
+                                error!(
+                                    "Error when sending packet to client `{}`: {}",
+                                    client_id, e
+                                );
+                                to_drop.insert(client_id);
+                            }
+                            continue;
+                        }
+                    };
+                    // Nothing changes, the client is still answered
+                    if username == client.username {
+                        let sent = common::ServerNickChanged::new(client.id, &username, &username)
+                            .map_err(std::io::Error::other)
+                            .and_then(|changed_packet| client.send_packet(&changed_packet));
+                        if let Err(e) = sent {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                        }
+                        continue;
+                    }
+                    // The password of an account is only asked at registration
+                    let registered =
+                        accounts
+                            .as_ref()
+                            .and_then(|a| a.get(&username))
+                            .filter(|account| {
+                                protocol::username_key(&account.username)
+                                    != protocol::username_key(&client.username)
+                            });
+                    let renamed = match registered {
+                        Some(account) => Err((
+                            ReasonCode::InvalidUsername,
+                            format!(
+                                "`{}` is a registered username, log in with it instead",
+                                account.username
+                            ),
+                        )),
+                        None => usernames
+                            .rename(&client.username, &username, client_id)
+                            .map_err(|e| (e.code(), e.to_string())),
+                    };
+                    if let Err((code, reason)) = renamed {
+                        debug!("Client `{}` can't change username: {}", client_id, reason);
+                        if let Err(e) = client.send_error(code, &reason) {
+                            error!("Error when sending packet to client `{}`: {}", client_id, e);
+                            to_drop.insert(client_id);
+                        }
+                        continue;
+                    }
+                    info!(
+                        "Client `{}` changed username from `{}` to `{}`",
+                        client_id, client.username, username
+                    );
+                    let old_username = std::mem::replace(&mut client.username, username);
+                    if let Some(changed_packet) = built(common::ServerNickChanged::new(
+                        client.id,
+                        &old_username,
+                        &client.username,
+                    )) {
+                        broadcasts
+                            .push(Audience::With(Capabilities::NICK_CHANGES), &changed_packet);
+                    }
+                    // The others see the user leave and come back under the new name
+                    let message = format!("Now known as {}", client.username);
+                    if let Some(left_packet) = built(common::ServerUserLeft::new(
+                        client.id,
+                        &old_username,
+                        &message,
+                    )) {
+                        broadcasts
+                            .push(Audience::Without(Capabilities::NICK_CHANGES), &left_packet);
+                    }
+                    if let Some(joined_packet) =
+                        built(common::ServerUserJoined::new(client.id, &client.username))
+                    {
+                        broadcasts.push(
+                            Audience::Without(Capabilities::NICK_CHANGES),
+                            &joined_packet,
+                        );
+                    }
+                }
+                PacketOwned::ClientPrivateMessage(packet) => {
+                    if let Err((code, reason)) =
+                        client.check_registered(packet.client_id, packet.magic)
+                    {
+                        info!("Client `{}` sent wrong packet: {}", client_id, reason);
+                        client.disconnect(code, reason);
+                        to_drop.insert(client_id);
+                        continue;
+                    }
+                    let message = match validation::message(&packet.message) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            if let Err(e) = client.send_error(
+                                ReasonCode::InvalidMessage,
+                                &format!("Invalid message: {}", e),
+                            ) {
+                                error!(
+                                    "Error when sending packet to client `{}`: {}",
+                                    client_id, e
+                                );
+                                to_drop.insert(client_id);
+                            }
+                            continue;
+                        }
+                    };
+                    if let Some(private_packet) = built(common::ClientPrivateMessage::new(
+                        packet.client_id,
+                        packet.magic,
+                        packet.target_id,
+                        &packet.target,
+                        &message,
+                    )) {
+                        private_messages.push((client_id, private_packet.into_owned()));
+                    }
+                }
 
                 // Only server sending these packets => dropping client
                 PacketOwned::ServerRegistrationConfirmation(_)
+                | PacketOwned::ServerRegistrationRefusal(_)
+                | PacketOwned::ServerError(_)
+                | PacketOwned::ServerDisconnect(_)
+                | PacketOwned::ServerUserLeft(_)
+                | PacketOwned::ServerRoomJoined(_)
+                | PacketOwned::ServerRoomParted(_)
+                | PacketOwned::ServerPrivateMessage(_)
+                | PacketOwned::ServerUserJoined(_)
+                | PacketOwned::ServerRoster(_)
+                | PacketOwned::ServerHistory(_)
                 | PacketOwned::ServerBroadcastMessage(_)
+                | PacketOwned::ServerAuthChallenge(_)
+                | PacketOwned::ServerNickChanged(_)
                 | PacketOwned::HeartBeatRequest(_) => {
                     debug!("`{}` sent a server-only packet, dropping him", client_id);
+                    client.disconnect(
+                        ReasonCode::ServerOnlyPacket,
+                        "This packet can only be sent by a server",
+                    );
                     to_drop.insert(client_id);
                 }
                 _ => error!("Packet: {:?} isn\'t supported", packet.get_identifier()),
             }
         }
 
+        // Sending the private messages to their target only
+        for (sender_id, packet) in private_messages.drain(..) {
+            let target_id = if packet.target.is_empty() {
+                Some(packet.target_id)
+            } else {
+                usernames.owner(&packet.target)
+            };
+            let target = target_id
+                .filter(|id| !to_drop.contains(id))
+                .and_then(|id| clients.get(&id))
+                .filter(|c| c.connection_status == ConnectionStatus::HandShakeDone);
+            let error = match target {
+                None => Some((ReasonCode::UnknownUser, "No such user".to_string())),
+                Some(target) if !target.capabilities.contains(Capabilities::PRIVATE_MESSAGES) => {
+                    Some((
+                        ReasonCode::Unsupported,
+                        format!("`{}` can't receive private messages", target.username),
+                    ))
+                }
+                Some(_) => None,
+            };
+            if let Some((code, reason)) = error {
+                if let Some(sender) = clients.get_mut(&sender_id) {
+                    if let Err(e) = sender.send_error(code, &reason) {
+                        error!("Error when sending packet to client `{}`: {}", sender_id, e);
+                        to_drop.insert(sender_id);
+                    }
+                }
+                continue;
+            }
+            let target_id = target_id.unwrap();
+            let sender_username = match clients.get(&sender_id) {
+                Some(sender) => sender.username.clone(),
+                None => continue,
+            };
+            let private_packet = match built(common::ServerPrivateMessage::new(
+                sender_id,
+                &sender_username,
+                &packet.message,
+            )) {
+                Some(private_packet) => private_packet,
+                None => continue,
+            };
+            if let Some(target) = clients.get_mut(&target_id) {
+                if let Err(e) = target.send_packet(&private_packet) {
+                    error!("Error when sending packet to client `{}`: {}", target_id, e);
+                    to_drop.insert(target_id);
+                }
+            }
+        }
+
+        // Answering the roster requests
+        if !roster_requests.is_empty() {
+            let users = clients
+                .values()
+                .filter(|c| {
+                    c.connection_status == ConnectionStatus::HandShakeDone
+                        && !to_drop.contains(&c.id)
+                })
+                .filter_map(|c| built(common::RosterEntry::new(c.id, &c.username)))
+                .collect::<Vec<_>>();
+            // Encoded once for every client asking, `max_clients` keeps it in a frame
+            let mut roster = Vec::new();
+            if let Some(roster_packet) = built(common::ServerRoster::new(users)) {
+                if let Err(e) = roster_packet.write_frame_to_vec(&mut roster) {
+                    error!("Unable to encode the roster: {}", e);
+                }
+            }
+            for client_id in roster_requests.iter().filter(|_| !roster.is_empty()) {
+                if let Some(client) = clients.get_mut(client_id) {
+                    if let Err(e) = client.send(&roster) {
+                        error!("Error when sending packet to client `{}`: {}", client_id, e);
+                        to_drop.insert(*client_id);
+                    }
+                }
+            }
+        }
+
         // Looping over every client and message to broadcast them
         for client in clients.values_mut() {
-            for message in &message_to_broadcast {
-                if let Err(e) = client.con.write(message) {
+            // No need to send anything to a client that is leaving
+            if to_drop.contains(&client.id) {
+                continue;
+            }
+            for (audience, message) in broadcasts.iter() {
+                let for_client = match audience {
+                    Audience::Everyone => true,
+                    Audience::Room(room) => rooms.is_member(room, client.id),
+                    Audience::With(capability) => client.capabilities.contains(*capability),
+                    Audience::Without(capability) => !client.capabilities.contains(*capability),
+                };
+                if !for_client {
+                    continue;
+                }
+                if let Err(e) = client.send(message) {
                     error!("Error when sending packet to client `{}`: {}", client.id, e);
                     to_drop.insert(client.id);
+                    break;
                 }
             }
+            if to_drop.contains(&client.id) {
+                continue;
+            }
 
-            if client.lastheart_beat + HB_REQUEST_TIME > std::time::Instant::now() {
+            // Ask for a heartbeat when the client has been quiet for too long
+            if client.lastheart_beat.elapsed() >= hb_request_time
+                && client.lastheart_request.elapsed() >= hb_request_time
+            {
                 client.heartbeat_skipped += 1;
-                if client.heartbeat_skipped >= MAX_HB_SKIP {
+                client.lastheart_request = Instant::now();
+                if client.heartbeat_skipped >= config.heartbeat.max_skipped {
                     debug!("Client `{}` not responding to HeatBeat", client.id);
+                    client.disconnect(ReasonCode::HeartBeatTimeout, "Not responding to heartbeats");
                     to_drop.insert(client.id);
-                }
-                if let Err(e) = client.send_hearbeat() {
+                } else if let Err(e) = client.send_hearbeat() {
                     error!("Error when sending packet to client `{}`: {}", client.id, e);
                     to_drop.insert(client.id);
                 }
             }
         }
+        broadcasts.clear();
 
-        for client_id in &need_hearbeat {
-            let client = clients.get_mut(client_id);
-            if client.is_none() {
+        // Dropping every client in the drop list, the others learn about it next loop
+        for client_id in &to_drop {
+            rooms.leave_all(*client_id);
+            let mut client = match clients.remove(client_id) {
+                Some(client) => client,
+                None => continue,
+            };
+            usernames.release(&client.username, client.id);
+            if let Err(e) = poll.registry().deregister(&mut client.con) {
+                debug!("Unable to deregister client `{}`: {}", client_id, e);
+            }
+            if client.connection_status != ConnectionStatus::HandShakeDone {
                 continue;
             }
-            let client = client.unwrap();
-            if let Err(e) = client.send_hearbeat() {
-                error!("Error when sending packet to client `{}`: {}", client_id, e);
-                to_drop.insert(*client_id);
+            if let Some(left_packet) = built(common::ServerUserLeft::new(
+                client.id,
+                &client.username,
+                &client.quit_message,
+            )) {
+                broadcasts.push(Audience::Everyone, &left_packet);
             }
         }
 
-        // Dropping every client in the drop list
-        for client_id in &to_drop {
-            clients.remove(client_id);
-        }
-
         if need_clear_hb_skip {
-            last_clear = std::time::Instant::now();
+            last_clear = Instant::now();
         }
 
         if STOPPING.load(Ordering::SeqCst) {
+            for client in clients.values_mut() {
+                client.disconnect(ReasonCode::ServerShutdown, "Server is shutting down");
+            }
             break 'mainloop;
         }
     }
 }
 
+/// The packet built out of validated values, or the length that doesn't fit is logged
+fn built<T>(packet: Result<T, common::LengthError>) -> Option<T> {
+    packet
+        .map_err(|e| error!("Unable to build a packet: {}", e))
+        .ok()
+}
+
+/// Milliseconds since the Unix epoch
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Send the logs where the configuration asks
+fn init_logger(log: &config::Log) -> Result<(), Box<dyn std::error::Error>> {
+    match &log.file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)?;
+            simplelog::WriteLogger::init(log.level, Default::default(), file)?;
+        }
+        None => {
+            println!();
+            simplelog::TermLogger::init(
+                log.level,
+                Default::default(),
+                simplelog::TerminalMode::Mixed,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Set the password of an account, read on the first line of the standard input
+fn add_account(config: &Config, username: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = config
+        .accounts
+        .file
+        .as_ref()
+        .ok_or("no accounts file is configured")?;
+    let username = validation::username(username)
+        .map_err(|e| format!("`{}` isn't a valid username: {}", username, e))?;
+    if protocol::is_reserved_username(&username) {
+        return Err(format!("`{}` is reserved", username).into());
+    }
+    let mut accounts = Accounts::open(path)?;
+    eprint!("Password for `{}`: ", username);
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        return Err("the password is empty".into());
+    }
+    accounts.set_password(&username, password);
+    accounts.save()?;
+    eprintln!("Account `{}` saved in `{}`", username, path.display());
+    Ok(())
+}
+
 fn generate_client_id() -> ClientID {
     use rand::prelude::*;
     rand::thread_rng().gen_range(0x00000000..=0xFF000000) << 1
@@ -321,46 +1352,70 @@ fn generate_client_magic() -> u32 {
     rand::thread_rng().gen()
 }
 
-fn generate_connection_handler(
-    ip: impl net::ToSocketAddrs,
-) -> (
-    impl FnOnce() -> (),
-    crossbeam_channel::Receiver<net::TcpStream>,
+/// Accept every pending connection and register it in the event loop
+fn accept_clients(
+    listener: &Listener,
+    registry: &mio::Registry,
+    clients: &mut HashMap<ClientID, Client>,
+    limits: &config::Limits,
 ) {
-    let (sx, rx) = crossbeam_channel::unbounded();
-
-    (
-        move || {
-            let listener = net::TcpListener::bind(ip);
-            if let Err(e) = listener.as_ref() {
-                error!("Error when binding to given IP: {}", e);
+    loop {
+        let (mut con, addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Error when client connected: {}", e);
                 return;
             }
-            let listener = listener.unwrap();
-            match listener.local_addr() {
-                Ok(ip) => info!("Binded on {}", ip),
-                Err(e) => warn!("Unable to get local binding: {}", e),
-            }
-            for con in listener.incoming() {
-                if STOPPING.load(Ordering::SeqCst) {
-                    break;
-                }
-                if let Err(e) = con.as_ref() {
-                    error!("Error when client connected: {}", e);
-                    continue;
-                }
-                let con = con.unwrap();
-                if let Ok(addr) = con.peer_addr() {
-                    debug!("Accepted new connection: [{}]", addr);
-                } else {
-                    debug!("Accepted new connection: [NO ADDR]",);
-                }
-                match sx.send(con) {
-                    Ok(_) => {}
-                    Err(e) => error!("Error when passing connection: {}", e),
-                };
+        };
+        debug!("Accepted new connection: [{}]", addr);
+        if clients.len() >= limits.max_clients {
+            warn!("Refusing [{}], the server is full", addr);
+            // Nothing was written on the socket yet, this can't block
+            if let Some(disconnect) = built(common::ServerDisconnect::new(
+                ReasonCode::ServerFull.code(),
+                "The server is full",
+            )) {
+                let _ = disconnect.write_frame_to(&mut con);
             }
-        },
-        rx,
-    )
+            continue;
+        }
+        // Generate a id for the new client
+        let mut new_id: ClientID = generate_client_id();
+        // if the id already exist or is the token of a listener, generate a new one
+        while clients.contains_key(&new_id) || (new_id as usize) < FIRST_CLIENT {
+            new_id = generate_client_id();
+        }
+        if let Err(e) = registry.register(
+            &mut con,
+            Token(new_id as usize),
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!("Unable to register new connection: {}", e);
+            continue;
+        }
+        let magic = generate_client_magic();
+        // Add new client to the clients hashmap
+        clients.insert(
+            new_id,
+            Client {
+                con,
+                id: new_id,
+                connection_status: ConnectionStatus::WaitingForClientVerification,
+                heartbeat_skipped: 0,
+                lastheart_beat: Instant::now(),
+                lastheart_request: Instant::now(),
+                magic,
+                username: String::new(),
+                protocol_version: protocol::PROTOCOL_VERSION,
+                capabilities: Capabilities::NONE,
+                challenge: None,
+                decoder: PacketDecoder::new(),
+                outbound: OutboundQueue::new(limits.outbound_high_water),
+                encoded: Vec::new(),
+                quit_message: String::new(),
+            },
+        );
+    }
 }
@@ -0,0 +1,126 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+/// Frames waiting to be written to a client socket.
+///
+/// Frames are only accepted while the queued bytes stay under the high-water
+/// mark, a client that doesn't read fast enough must be disconnected.
+/// A frame sent while nothing is waiting goes straight to the socket, only
+/// what the socket doesn't take is copied.
+#[derive(Debug)]
+pub(crate) struct OutboundQueue {
+    frames: VecDeque<Vec<u8>>,
+    // bytes of the front frame already written
+    written: usize,
+    // the front frame is partly on the wire, maybe from before it was queued
+    started: bool,
+    // bytes queued and not written yet
+    len: usize,
+    high_water: usize,
+}
+
+impl OutboundQueue {
+    pub(crate) fn new(high_water: usize) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            written: 0,
+            started: false,
+            len: 0,
+            high_water,
+        }
+    }
+
+    /// Queue a frame, returns false without queuing it if it would go over the high-water mark
+    pub(crate) fn push(&mut self, frame: &[u8]) -> bool {
+        if self.len + frame.len() > self.high_water {
+            return false;
+        }
+        self.force_push(frame);
+        true
+    }
+
+    /// Write a frame after the ones waiting, queuing what the writer doesn't take.
+    ///
+    /// Returns false if the frame doesn't fit under the high-water mark, a frame
+    /// the writer took a part of is kept whole anyway to not cut it.
+    pub(crate) fn send(&mut self, writer: &mut impl Write, frame: &[u8]) -> io::Result<bool> {
+        self.flush(writer)?;
+        if !self.frames.is_empty() {
+            return Ok(self.push(frame));
+        }
+        let mut sent = 0;
+        while sent < frame.len() {
+            match writer.write(&frame[sent..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if sent == 0 {
+            return Ok(self.push(frame));
+        }
+        if sent < frame.len() {
+            self.force_push(&frame[sent..]);
+            self.started = true;
+        }
+        flush_writer(writer)?;
+        Ok(self.len <= self.high_water)
+    }
+
+    /// Queue a frame whatever the high-water mark
+    pub(crate) fn force_push(&mut self, frame: &[u8]) {
+        self.len += frame.len();
+        self.frames.push_back(frame.to_vec());
+    }
+
+    /// Forget every frame not started yet, a partially written frame is kept to not cut it
+    pub(crate) fn discard_unsent(&mut self) {
+        let keep = if self.started { 1 } else { 0 };
+        self.frames.truncate(keep);
+        self.len = self
+            .frames
+            .front()
+            .map(|f| f.len() - self.written)
+            .unwrap_or(0);
+    }
+
+    /// Write the queued frames until the writer would block, then flush it
+    pub(crate) fn flush(&mut self, writer: &mut impl Write) -> io::Result<()> {
+        while let Some(frame) = self.frames.front() {
+            match writer.write(&frame[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+                    self.started = true;
+                    self.len -= n;
+                    if self.written == frame.len() {
+                        self.frames.pop_front();
+                        self.written = 0;
+                        self.started = false;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        flush_writer(writer)
+    }
+
+    /// Number of bytes waiting to be written
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+// Push out what the writer buffers, a socket that would block is retried later
+fn flush_writer(writer: &mut impl Write) -> io::Result<()> {
+    match writer.flush() {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        result => result,
+    }
+}
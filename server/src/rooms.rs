@@ -0,0 +1,64 @@
+use std::collections::{HashMap, HashSet};
+
+use common::validation;
+
+use crate::ClientID;
+
+// the maximum length of a room name in bytes
+pub(crate) const MAX_ROOM_LEN: usize = 32;
+
+/// Membership of every room, a room exists as long as it has members
+#[derive(Debug, Default)]
+pub(crate) struct Rooms {
+    rooms: HashMap<String, HashSet<ClientID>>,
+}
+
+impl Rooms {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the client to the room, returns false if it already was a member
+    pub(crate) fn join(&mut self, room: &str, client_id: ClientID) -> bool {
+        self.rooms
+            .entry(room.to_string())
+            .or_default()
+            .insert(client_id)
+    }
+
+    /// Remove the client from the room, returns false if it wasn't a member
+    pub(crate) fn part(&mut self, room: &str, client_id: ClientID) -> bool {
+        let members = match self.rooms.get_mut(room) {
+            Some(members) => members,
+            None => return false,
+        };
+        let removed = members.remove(&client_id);
+        if members.is_empty() {
+            self.rooms.remove(room);
+        }
+        removed
+    }
+
+    /// Remove the client from every room it joined
+    pub(crate) fn leave_all(&mut self, client_id: ClientID) {
+        self.rooms.retain(|_, members| {
+            members.remove(&client_id);
+            !members.is_empty()
+        });
+    }
+
+    pub(crate) fn is_member(&self, room: &str, client_id: ClientID) -> bool {
+        self.rooms
+            .get(room)
+            .map(|members| members.contains(&client_id))
+            .unwrap_or(false)
+    }
+}
+
+pub(crate) fn is_valid_room_name(room: &str) -> bool {
+    !room.is_empty()
+        && room.len() <= MAX_ROOM_LEN
+        && !room
+            .chars()
+            .any(|c| c.is_whitespace() || validation::is_control(c))
+}
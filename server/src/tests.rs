@@ -0,0 +1,682 @@
+mod history {
+    use crate::history::*;
+    use common::ServerBroadcastMessageOwned;
+
+    fn message(message_id: u64, room: &str) -> ServerBroadcastMessageOwned {
+        ServerBroadcastMessageOwned {
+            message_id,
+            timestamp: 0x00000175_3F8A2B00,
+            user_id: 0xFFDD00FF,
+            room_len: room.len() as u8,
+            room: room.to_string(),
+            username_len: 4,
+            username: "Maix".to_string(),
+            message_len: 5,
+            message: "hello".to_string(),
+        }
+    }
+
+    fn ids(messages: Vec<ServerBroadcastMessageOwned>) -> Vec<u64> {
+        messages.into_iter().map(|m| m.message_id).collect()
+    }
+
+    #[test]
+    fn memory() {
+        let mut history = MemoryHistory::new(3);
+        assert_eq!(history.last_id(), None);
+        for id in 1..=5 {
+            history.push(message(id, "dev")).unwrap();
+        }
+        history.push(message(6, "general")).unwrap();
+
+        assert_eq!(ids(history.last("dev", 10)), vec![3, 4, 5]);
+        assert_eq!(ids(history.last("dev", 2)), vec![4, 5]);
+        assert_eq!(ids(history.last("general", 10)), vec![6]);
+        assert!(history.last("nope", 10).is_empty());
+        assert_eq!(history.last_id(), Some(6));
+
+        assert_eq!(ids(history.before("dev", 5, 10).unwrap()), vec![3, 4]);
+        assert_eq!(ids(history.before("dev", 5, 1).unwrap()), vec![4]);
+        assert!(history.before("dev", 3, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pages() {
+        let mut history = MemoryHistory::new(10);
+        for id in 1..=5 {
+            history.push(message(id, "dev")).unwrap();
+        }
+        // The server sends less than asked, scrolling goes on until an empty page
+        let mut before = u64::MAX;
+        let mut pages = Vec::new();
+        loop {
+            let page = ids(history.page("dev", before, 50, 2).unwrap());
+            match page.first() {
+                Some(&oldest) => before = oldest,
+                None => break,
+            }
+            pages.push(page);
+        }
+        assert_eq!(pages, vec![vec![4, 5], vec![2, 3], vec![1]]);
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("maix-chat-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut history = FileHistory::open(&path, 2).unwrap();
+            for id in 1..=3 {
+                history.push(message(id, "dev")).unwrap();
+            }
+            assert_eq!(ids(history.last("dev", 10)), vec![2, 3]);
+        }
+
+        // A crash in the middle of a write leaves a truncated frame
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(b"MC\x00\x00");
+        std::fs::write(&path, data).unwrap();
+
+        {
+            let mut history = FileHistory::open(&path, 2).unwrap();
+            assert_eq!(ids(history.last("dev", 10)), vec![2, 3]);
+            assert_eq!(history.last_id(), Some(3));
+            // Evicted from the cache, read back from the log
+            assert_eq!(ids(history.before("dev", 3, 10).unwrap()), vec![1, 2]);
+            assert_eq!(ids(history.before("dev", 3, 1).unwrap()), vec![2]);
+            history.push(message(4, "general")).unwrap();
+            history.push(message(5, "dev")).unwrap();
+            assert_eq!(ids(history.before("dev", 5, 10).unwrap()), vec![1, 2, 3]);
+            assert!(history.before("general", 4, 10).unwrap().is_empty());
+        }
+
+        // The truncated frame is gone, it doesn't hide the messages written after it
+        let history = FileHistory::open(&path, 1).unwrap();
+        assert_eq!(history.last_id(), Some(5));
+        assert_eq!(ids(history.before("dev", 6, 10).unwrap()), vec![1, 2, 3, 5]);
+        assert_eq!(ids(history.last("general", 10)), vec![4]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+mod accounts {
+    use crate::accounts::*;
+    use common::auth;
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("maix-chat-accounts-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut accounts = Accounts::open(&path).unwrap();
+            assert_eq!(accounts.len(), 0);
+            accounts.set_password("Maix", "hunter2");
+            accounts.set_password("Ahmed", "secret");
+            accounts.save().unwrap();
+        }
+
+        let accounts = Accounts::open(&path).unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert!(accounts.get("Nobody").is_none());
+        let account = accounts.get("Maix").unwrap();
+        let nonce = [7u8; auth::NONCE_LEN];
+        let proof =
+            auth::client_proof("hunter2", &account.salt, account.iterations, "Maix", &nonce)
+                .unwrap();
+        assert!(auth::verify_proof(
+            &account.stored_key,
+            "Maix",
+            &nonce,
+            &proof
+        ));
+        let proof = auth::client_proof("secret", &account.salt, account.iterations, "Maix", &nonce)
+            .unwrap();
+        assert!(!auth::verify_proof(
+            &account.stored_key,
+            "Maix",
+            &nonce,
+            &proof
+        ));
+
+        std::fs::write(&path, "# comment\n\nMaix 00 1 00\n").unwrap();
+        assert_eq!(
+            Accounts::open(&path).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+mod usernames {
+    use crate::usernames::*;
+
+    #[test]
+    fn unique() {
+        let mut usernames = Usernames::new();
+        usernames.claim("Maix", 1).unwrap();
+        assert_eq!(usernames.owner("mAIX"), Some(1));
+        assert_eq!(
+            usernames.claim("maix", 2),
+            Err(UsernameError::Taken {
+                username: "maix".to_string(),
+                suggestion: Some("maix_2".to_string())
+            })
+        );
+        usernames.claim("maix_2", 2).unwrap();
+        assert_eq!(
+            usernames.claim("MAIX", 3).unwrap_err().to_string(),
+            "`MAIX` is already taken, `MAIX_3` is free"
+        );
+        assert_eq!(usernames.claim("", 3), Err(UsernameError::Empty));
+        assert_eq!(
+            usernames.claim("system", 3),
+            Err(UsernameError::Reserved("system".to_string()))
+        );
+
+        // A change of case keeps the username
+        usernames.rename("Maix", "MAIX", 1).unwrap();
+        assert_eq!(usernames.owner("maix"), Some(1));
+        assert!(usernames.rename("MAIX", "Maix_2", 1).is_err());
+        usernames.rename("MAIX", "Ahmed", 1).unwrap();
+        assert_eq!(usernames.owner("maix"), None);
+
+        // Only the owner frees a username
+        usernames.release("ahmed", 2);
+        assert_eq!(usernames.owner("Ahmed"), Some(1));
+        usernames.release("ahmed", 1);
+        assert_eq!(usernames.owner("Ahmed"), None);
+
+        // A suggestion for the longest username is still a valid one
+        let long = "é".repeat(common::validation::MAX_USERNAME_CHARS);
+        usernames.claim(&long, 1).unwrap();
+        match usernames.claim(&long, 2) {
+            Err(UsernameError::Taken {
+                suggestion: Some(suggestion),
+                ..
+            }) => {
+                assert_eq!(suggestion, format!("{}_2", "é".repeat(28)));
+                assert_eq!(common::validation::username(&suggestion), Ok(suggestion));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
+
+mod rooms {
+    use crate::rooms::*;
+
+    #[test]
+    fn room_names() {
+        assert!(is_valid_room_name("general"));
+        assert!(!is_valid_room_name(""));
+        assert!(!is_valid_room_name("dev ops"));
+        assert!(!is_valid_room_name("dev\x1B[2J"));
+        // Invisible characters would let two rooms look the same
+        assert!(!is_valid_room_name("gen\u{200B}eral"));
+        assert!(!is_valid_room_name("\u{202E}lareneg"));
+        assert!(!is_valid_room_name(&"a".repeat(33)));
+    }
+}
+
+mod broadcast {
+    use crate::broadcast::*;
+    use common::{frame::IntoFrame, protocol::Capabilities, ServerMotd};
+
+    #[test]
+    fn frames() {
+        let mut broadcasts = Broadcasts::new();
+        assert!(broadcasts.is_empty());
+        let hello = ServerMotd::new("hello").unwrap();
+        let bye = ServerMotd::new("bye").unwrap();
+        broadcasts.push(Audience::Everyone, &hello);
+        // A packet that can't be encoded isn't queued
+        broadcasts.push(
+            Audience::Everyone,
+            &ServerMotd {
+                message_len: 1,
+                message: "oops",
+            },
+        );
+        broadcasts.push(Audience::With(Capabilities::NICK_CHANGES), &bye);
+        let frames = broadcasts.iter().collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            vec![
+                (&Audience::Everyone, hello.unwrap_frame().as_slice()),
+                (
+                    &Audience::With(Capabilities::NICK_CHANGES),
+                    bye.unwrap_frame().as_slice()
+                ),
+            ]
+        );
+
+        broadcasts.clear();
+        assert!(broadcasts.is_empty());
+        assert_eq!(broadcasts.iter().count(), 0);
+    }
+}
+
+mod outbound {
+    use crate::outbound::OutboundQueue;
+    use std::io::{self, Write};
+
+    // Accepts `capacity` bytes then would block
+    struct SlowWriter {
+        data: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.capacity - self.data.len());
+            if n == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes() {
+        let mut queue = OutboundQueue::new(16);
+        let mut writer = SlowWriter {
+            data: Vec::new(),
+            capacity: 5,
+        };
+        assert!(queue.push(b"abcd"));
+        assert!(queue.push(b"efgh"));
+        queue.flush(&mut writer).unwrap();
+        assert_eq!(writer.data, b"abcde");
+        assert_eq!(queue.len(), 3);
+
+        writer.capacity = 100;
+        queue.flush(&mut writer).unwrap();
+        assert_eq!(writer.data, b"abcdefgh");
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn high_water() {
+        let mut queue = OutboundQueue::new(8);
+        assert!(queue.push(b"abcd"));
+        assert!(queue.push(b"efgh"));
+        assert!(!queue.push(b"i"));
+        assert_eq!(queue.len(), 8);
+
+        let mut writer = SlowWriter {
+            data: Vec::new(),
+            capacity: 2,
+        };
+        queue.flush(&mut writer).unwrap();
+        // The frame being written is finished before anything else
+        queue.discard_unsent();
+        queue.force_push(b"bye");
+        writer.capacity = 100;
+        queue.flush(&mut writer).unwrap();
+        assert_eq!(writer.data, b"abcdbye");
+    }
+
+    #[test]
+    fn direct_sends() {
+        let mut queue = OutboundQueue::new(4);
+        let mut writer = SlowWriter {
+            data: Vec::new(),
+            capacity: 6,
+        };
+        // Nothing waits, the frame goes straight out even bigger than the mark
+        assert!(queue.send(&mut writer, b"abcde").unwrap());
+        assert_eq!(queue.len(), 0);
+        // Only the rest is queued, and it is kept whole when the queue is cut
+        assert!(queue.send(&mut writer, b"fgh").unwrap());
+        assert_eq!(writer.data, b"abcdef");
+        assert_eq!(queue.len(), 2);
+        assert!(!queue.send(&mut writer, b"ijk").unwrap());
+        queue.discard_unsent();
+        assert_eq!(queue.len(), 2);
+
+        writer.capacity = 100;
+        assert!(queue.send(&mut writer, b"ijk").unwrap());
+        assert_eq!(writer.data, b"abcdefghijk");
+        assert_eq!(queue.len(), 0);
+    }
+}
+
+mod inbound {
+    use crate::inbound::*;
+    use common::{decoder::PacketDecoder, frame::IntoFrame, ServerMotd};
+    use std::io::{self, Read};
+
+    // Hands out `data` then would block, or says the stream is closed
+    struct Socket {
+        data: Vec<u8>,
+        closed: bool,
+    }
+
+    impl Read for Socket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() && !self.closed {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_limit() {
+        let frame = ServerMotd::new("hello").unwrap().unwrap_frame();
+        let mut socket = Socket {
+            data: frame.repeat(10),
+            closed: false,
+        };
+        let mut decoder = PacketDecoder::new();
+        let mut buffer = [0u8; 8];
+
+        // A client sending without pause is read a bit at a time
+        let status = read_into(&mut socket, &mut decoder, &mut buffer, frame.len() * 4).unwrap();
+        assert_eq!(status, ReadStatus::Pending);
+        assert_eq!(decoder.buffered(), frame.len() * 4);
+        let status = read_into(&mut socket, &mut decoder, &mut buffer, frame.len() * 10).unwrap();
+        assert_eq!(status, ReadStatus::Drained);
+        let mut packets = 0;
+        while decoder.next_packet().unwrap().is_some() {
+            packets += 1;
+        }
+        assert_eq!(packets, 10);
+
+        socket.closed = true;
+        let status = read_into(&mut socket, &mut decoder, &mut buffer, 64).unwrap();
+        assert_eq!(status, ReadStatus::Closed);
+    }
+}
+
+mod config {
+    use crate::config::*;
+    use crate::listener::ListenAddr;
+    use clap::Parser;
+    use log::LevelFilter;
+
+    const FILE: &str = r#"
+listen = ["[::1]:9000", "unix:/tmp/maix-chat.sock"]
+unix_socket_mode = 0o600
+motd = "Welcome!"
+
+[limits]
+max_clients = 10
+
+[heartbeat]
+interval_ms = 1000
+max_skipped = 3
+
+[history]
+file = "history.log"
+replay = 20
+
+[log]
+level = "info"
+"#;
+
+    #[test]
+    fn file() {
+        let config: Config = toml::from_str(FILE).unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                ListenAddr::Tcp("[::1]:9000".parse().unwrap()),
+                ListenAddr::Unix("/tmp/maix-chat.sock".into())
+            ]
+        );
+        assert_eq!(config.unix_socket_mode, 0o600);
+        assert_eq!(config.motd.as_deref(), Some("Welcome!"));
+        assert_eq!(config.limits.max_clients, 10);
+        assert_eq!(config.limits.outbound_high_water, 1 << 20);
+        assert_eq!(config.heartbeat.interval_ms, 1000);
+        assert_eq!(config.heartbeat.max_skipped, 3);
+        assert_eq!(config.heartbeat.reset_ms, 30_000);
+        assert_eq!(config.history.file, Some("history.log".into()));
+        assert_eq!(config.history.replay, 20);
+        assert_eq!(config.log.level, LevelFilter::Info);
+
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+        assert!(toml::from_str::<Config>("[limits]\nmax_client = 10").is_err());
+        assert!(toml::from_str::<Config>("listen = [\"localhost\"]").is_err());
+        assert!(toml::from_str::<Config>("listen = [\"unix:\"]").is_err());
+    }
+
+    #[test]
+    fn args() {
+        let mut config: Config = toml::from_str(FILE).unwrap();
+        config.apply(&Args::parse_from([
+            "maix-chat-server",
+            "--listen",
+            "0.0.0.0:8888",
+            "-l",
+            "[::]:8888",
+            "--unix-socket-mode",
+            "666",
+            "--heartbeat-max-skipped",
+            "4",
+            "--log-level",
+            "warn",
+        ]));
+        assert_eq!(
+            config.listen,
+            vec![
+                ListenAddr::Tcp("0.0.0.0:8888".parse().unwrap()),
+                ListenAddr::Tcp("[::]:8888".parse().unwrap())
+            ]
+        );
+        assert_eq!(config.unix_socket_mode, 0o666);
+        assert_eq!(config.heartbeat.max_skipped, 4);
+        assert_eq!(config.heartbeat.interval_ms, 1000);
+        assert_eq!(config.log.level, LevelFilter::Warn);
+    }
+
+    #[test]
+    fn validate() {
+        Config::default().validate().unwrap();
+
+        let mut config = Config::default();
+        config.heartbeat.reset_ms = 10_000;
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "heartbeat.reset_ms"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut config = Config::default();
+        config.listen.push(config.listen[0].clone());
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "listen"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Every listener needs a token below the ones of the clients
+        let mut config = Config {
+            listen: (0..=MAX_LISTEN as u16)
+                .map(|port| format!("127.0.0.1:{}", 9000 + port).parse().unwrap())
+                .collect(),
+            ..Config::default()
+        };
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "listen"),
+            other => panic!("unexpected {:?}", other),
+        }
+        config.listen.pop();
+        config.validate().unwrap();
+
+        let mut config = Config::default();
+        config.listen.push("tls:0.0.0.0:8889".parse().unwrap());
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "tls.cert"),
+            other => panic!("unexpected {:?}", other),
+        }
+        config.tls.cert = Some("cert.pem".into());
+        config.tls.key = Some("key.pem".into());
+        config.validate().unwrap();
+
+        let mut config = Config::default();
+        config.accounts.allow_guests = false;
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "accounts.file"),
+            other => panic!("unexpected {:?}", other),
+        }
+        config.accounts.file = Some("accounts.txt".into());
+        config.validate().unwrap();
+
+        // A page of the longest messages must fit in the outbound queue
+        let mut config = Config::default();
+        config.limits.outbound_high_water = 1 << 16;
+        config.limits.max_clients = 100;
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "history.page_limit"),
+            other => panic!("unexpected {:?}", other),
+        }
+        config.history.page_limit = 7;
+        config.validate().unwrap();
+        // and in a frame the client accepts
+        let mut config = Config::default();
+        config.limits.outbound_high_water = 1 << 24;
+        config.history.page_limit = 200;
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "history.page_limit"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // The roster of every client must fit in a frame
+        let mut config = Config::default();
+        config.limits.max_clients = 70_000;
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "limits.max_clients"),
+            other => panic!("unexpected {:?}", other),
+        }
+        // and in the outbound queue
+        let mut config = Config::default();
+        config.limits.outbound_high_water = 1 << 16;
+        config.history.page_limit = 7;
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "limits.max_clients"),
+            other => panic!("unexpected {:?}", other),
+        }
+        config.limits.max_clients = 250;
+        config.validate().unwrap();
+
+        let mut config = Config::default();
+        config.limits.outbound_high_water = 1024;
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => {
+                assert_eq!(key, "limits.outbound_high_water")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod listener {
+    use crate::listener::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn unix() {
+        let path = std::env::temp_dir().join(format!("maix-chat-{}.sock", std::process::id()));
+        let addr: ListenAddr = format!("unix:{}", path.display()).parse().unwrap();
+        // A socket left behind is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let listener = Listener::bind(&addr, 0o600, None).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let _client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        assert!(matches!(listener.accept(), Ok((Connection::Unix(_), _))));
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
+
+mod tls {
+    use crate::listener::*;
+    use crate::outbound::OutboundQueue;
+    use std::{
+        io::{self, Read, Write},
+        sync::Arc,
+        time::Duration,
+    };
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("maix-chat-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), generated.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), generated.key_pair.serialize_pem()).unwrap();
+        assert!(crate::tls::server_config(&dir.join("cert.pem"), &dir.join("cert.pem")).is_err());
+        let config =
+            crate::tls::server_config(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let listener =
+            Listener::bind(&"tls:127.0.0.1:0".parse().unwrap(), 0, Some(&config)).unwrap();
+        let addr: std::net::SocketAddr = listener.to_string()["tls:".len()..].parse().unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(generated.cert.der().clone()).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let client = std::thread::spawn(move || {
+            let conn = rustls::ClientConnection::new(
+                Arc::new(client_config),
+                std::convert::TryFrom::try_from("localhost").unwrap(),
+            )
+            .unwrap();
+            let mut stream =
+                rustls::StreamOwned::new(conn, std::net::TcpStream::connect(addr).unwrap());
+            stream.write_all(b"ping").unwrap();
+            let mut answer = [0u8; 4];
+            stream.read_exact(&mut answer).unwrap();
+            answer
+        });
+
+        let mut con = loop {
+            match listener.accept() {
+                Ok((con, _)) => break con,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 16];
+        while received.len() < 4 {
+            match con.read(&mut buffer) {
+                Ok(n) => received.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(received, b"ping");
+
+        let mut queue = OutboundQueue::new(16);
+        assert!(queue.push(b"pong"));
+        queue.flush(&mut con).unwrap();
+        assert_eq!(queue.len(), 0);
+        assert_eq!(&client.join().unwrap(), b"pong");
+    }
+}
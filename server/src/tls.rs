@@ -0,0 +1,118 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use mio::net::TcpStream;
+use rustls::{ServerConfig, ServerConnection};
+
+/// Load the certificate chain and private key of the server, both PEM encoded
+pub(crate) fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate in `{}`", cert.display()),
+        ));
+    }
+    let key =
+        rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no private key in `{}`", key.display()),
+            )
+        })?;
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(config))
+}
+
+/// TLS over a non-blocking socket.
+///
+/// Reading and writing return `WouldBlock` like the socket does, the handshake is done along
+/// the way. Data rustls couldn't write yet is sent by `flush`.
+#[derive(Debug)]
+pub(crate) struct TlsStream {
+    conn: ServerConnection,
+    sock: TcpStream,
+}
+
+impl TlsStream {
+    pub(crate) fn new(config: Arc<ServerConfig>, sock: TcpStream) -> io::Result<Self> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Self { conn, sock })
+    }
+
+    pub(crate) fn socket(&mut self) -> &mut TcpStream {
+        &mut self.sock
+    }
+
+    /// Write the encrypted data waiting in rustls
+    fn write_pending(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut self.sock)? == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // Tell the client the connection was closed on purpose
+        self.conn.send_close_notify();
+        let _ = self.write_pending();
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            if self.conn.read_tls(&mut self.sock)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.conn.process_new_packets() {
+                // Let the peer know why before giving up
+                let _ = self.write_pending();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            // Handshake messages, the rest is sent once the socket is writable
+            match self.write_pending() {
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Nothing more is taken while the socket is full
+        self.write_pending()?;
+        let n = self.conn.writer().write(buf)?;
+        match self.write_pending() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+            _ => {}
+        }
+        if n == 0 && !buf.is_empty() {
+            // rustls buffers are full until the handshake is done
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use common::{
+    protocol::{is_reserved_username, username_key, ReasonCode},
+    validation::{MAX_USERNAME_BYTES, MAX_USERNAME_CHARS},
+};
+
+use crate::ClientID;
+
+// how many numbered alternatives are tried for a taken username
+const MAX_SUGGESTIONS: u32 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub(crate) enum UsernameError {
+    #[error("The username can't be empty")]
+    Empty,
+    #[error("`{0}` is reserved")]
+    Reserved(String),
+    #[error("`{username}` is already taken{}", suggestion_hint(.suggestion))]
+    Taken {
+        username: String,
+        suggestion: Option<String>,
+    },
+}
+
+impl UsernameError {
+    pub(crate) fn code(&self) -> ReasonCode {
+        match self {
+            UsernameError::Empty | UsernameError::Reserved(_) => ReasonCode::InvalidUsername,
+            UsernameError::Taken { .. } => ReasonCode::UsernameTaken,
+        }
+    }
+}
+
+fn suggestion_hint(suggestion: &Option<String>) -> String {
+    match suggestion {
+        Some(suggestion) => format!(", `{}` is free", suggestion),
+        None => String::new(),
+    }
+}
+
+/// Usernames of the connected clients, unique regardless of case
+#[derive(Debug, Default)]
+pub(crate) struct Usernames {
+    owners: HashMap<String, ClientID>,
+}
+
+impl Usernames {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give the username to the client if nobody else has it
+    pub(crate) fn claim(
+        &mut self,
+        username: &str,
+        client_id: ClientID,
+    ) -> Result<(), UsernameError> {
+        if username.is_empty() {
+            return Err(UsernameError::Empty);
+        }
+        if is_reserved_username(username) {
+            return Err(UsernameError::Reserved(username.to_string()));
+        }
+        match self.owner(username) {
+            Some(owner) if owner != client_id => Err(UsernameError::Taken {
+                username: username.to_string(),
+                suggestion: self.suggest(username),
+            }),
+            _ => {
+                self.owners.insert(username_key(username), client_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Give the client `new` in place of `old`, it keeps `old` if `new` can't be used
+    pub(crate) fn rename(
+        &mut self,
+        old: &str,
+        new: &str,
+        client_id: ClientID,
+    ) -> Result<(), UsernameError> {
+        self.claim(new, client_id)?;
+        // A change of case only is still the same username
+        if username_key(old) != username_key(new) {
+            self.release(old, client_id);
+        }
+        Ok(())
+    }
+
+    /// Free the username, if the client has it
+    pub(crate) fn release(&mut self, username: &str, client_id: ClientID) {
+        let key = username_key(username);
+        if self.owners.get(&key) == Some(&client_id) {
+            self.owners.remove(&key);
+        }
+    }
+
+    /// The client with this username, whatever its case
+    pub(crate) fn owner(&self, username: &str) -> Option<ClientID> {
+        self.owners.get(&username_key(username)).copied()
+    }
+
+    /// A free username made of `username` and a number, valid for `validation::username`
+    fn suggest(&self, username: &str) -> Option<String> {
+        (2..MAX_SUGGESTIONS)
+            .map(|n| {
+                let suffix = format!("_{}", n);
+                let mut base = username;
+                while base.chars().count() + suffix.len() > MAX_USERNAME_CHARS
+                    || base.len() + suffix.len() > MAX_USERNAME_BYTES
+                {
+                    let last = base.char_indices().last().map_or(0, |(i, _)| i);
+                    base = &base[..last];
+                }
+                format!("{}{}", base, suffix)
+            })
+            .find(|candidate| self.owner(candidate).is_none())
+    }
+}